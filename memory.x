@@ -0,0 +1,6 @@
+MEMORY
+{
+    /* The last page (2K) of the flash holds the calibration of the NTC. */
+    FLASH : ORIGIN = 0x08000000, LENGTH = 126K
+    RAM   : ORIGIN = 0x20000000, LENGTH =  16K
+}
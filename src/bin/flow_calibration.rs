@@ -1,29 +1,44 @@
 #![no_std]
 #![no_main]
 
-use bambino_fw::{hardware::{
-    buttons::{self, ButtonState}, flow_meter::{self, FlowMeter}, heater::Heater, leds, pump, temperature::{self, Temperature}
-}, logic::temperature_pid::TemperaturePID};
+use core::cell::RefCell;
+
+use bambino_fw::{
+    hardware::{
+        board::Board,
+        buttons::{self, ButtonState},
+        leds, pump,
+    },
+    logic::{
+        control_loop::{ControlLoop, LoopMetrics, Periodic},
+        dispenser::Dispenser,
+        temperature_pid::TemperaturePID,
+    },
+    units::{Hertz, MilliCelsius, Milligrams, Percent},
+};
 use defmt::*;
 use embassy_executor::Spawner;
-use embassy_futures::select::select;
-use embassy_time::Timer;
+use embassy_futures::join::join;
+use embassy_time::Duration;
 use {defmt_rtt as _, panic_probe as _};
 
-#[embassy_executor::main]
-async fn main(mut spawner: Spawner) -> ! {
-    let _p = embassy_stm32::init(Default::default());
-    let mut pump = unsafe { pump::Pump::new() };
+static THERMAL_LOOP_METRICS: LoopMetrics = LoopMetrics::new();
+static DISPENSER_LOOP_METRICS: LoopMetrics = LoopMetrics::new();
+static METRICS_LOOP_METRICS: LoopMetrics = LoopMetrics::new();
 
-    let mut flow_meter = unsafe { FlowMeter::new(&mut spawner) };
+#[embassy_executor::main]
+async fn main(spawner: Spawner) -> ! {
+    let Board {
+        pump,
+        mut flow_meter,
+        temperature: temperatur,
+        mut heater,
+        mut buttons,
+        mut leds,
+        ..
+    } = Board::new(embassy_stm32::init(Default::default()), spawner).unwrap();
     flow_meter.enable();
 
-    let mut temperatur = unsafe { Temperature::new(&mut spawner) };
-
-    let mut heater = unsafe { Heater::new(&mut spawner) };
-
-    let mut buttons = unsafe { buttons::Buttons::new(&mut spawner) };
-    let mut leds = unsafe { leds::LEDs::new(&mut spawner) };
     leds.set_state_all(leds::LEDState::On);
 
     /*
@@ -41,60 +56,102 @@ async fn main(mut spawner: Spawner) -> ! {
     // Timer::after_millis(3000).await;
     // pump.disable();
 
-    let mut pid = TemperaturePID::new();
-    let mut start_flowed_value = 0;
+    let pid = RefCell::new(TemperaturePID::new());
+    let dispenser = RefCell::new((Dispenser::new(), pump));
 
-    pid.set_target_temperature(63);
+    pid.borrow_mut()
+        .set_target_temperature(MilliCelsius::from_celsius(63).unwrap());
 
-    loop {
-        let event = select(
-            buttons.wait_for_button_state_change(),
-            Timer::after_millis(50),
+    let mut thermal_loop = |dt| {
+        info!("temperatur={}°C", temperatur.temperature().as_celsius_f32());
+        let next_value = pid.borrow_mut().control(&temperatur, &mut heater, dt);
+        info!("pid_next_power_value={}%", next_value.get());
+    };
+    let mut dispenser_loop = |_| {
+        let (dispenser, pump) = &mut *dispenser.borrow_mut();
+        dispenser.update(pump, &flow_meter);
+    };
+    let mut metrics_loop = |_| {
+        info!(
+            "thermal_loop: iterations={} overruns={} max_jitter={}us max_execution_time={}us",
+            THERMAL_LOOP_METRICS.iterations(),
+            THERMAL_LOOP_METRICS.overruns(),
+            THERMAL_LOOP_METRICS.max_jitter().as_micros(),
+            THERMAL_LOOP_METRICS.max_execution_time().as_micros()
         );
-        match event.await {
-            embassy_futures::select::Either::First(event) => {
-                let new_state = event.new_state().state();
-                let source = event.new_state().source();
-                info!("Button {:?} is now in state {:?}", &source, new_state);
-
-                match source {
-                    buttons::ButtonKind::OneCup => {
-                        if new_state == ButtonState::Pressed {
-                            leds.set_state(leds::LEDKind::OneCup, leds::LEDState::Blinking(2));
-                            pump.set_power(pump::PumpPower::Fraction(0.5));
-                            start_flowed_value = flow_meter.flowed_mg();
-                            pump.enable();
-                        }
-                    }
-                    buttons::ButtonKind::TwoCup => {
-                        leds.set_state(leds::LEDKind::TwoCup, leds::LEDState::Blinking(3));
-                        if new_state == ButtonState::Pressed {
-                            leds.set_state(leds::LEDKind::OneCup, leds::LEDState::Blinking(2));
-                            pump.set_power(pump::PumpPower::Fraction(1.0));
-                            pump.enable();
-                            start_flowed_value = flow_meter.flowed_mg();
-                            pump.enable();
-                        }
-                    }
-                    buttons::ButtonKind::Steam => {
-                        pid.set_target_temperature(0);
+    };
+    let mut control_loop = ControlLoop::new([
+        Periodic::new(
+            Duration::from_millis(50),
+            &mut thermal_loop,
+            &THERMAL_LOOP_METRICS,
+        ),
+        Periodic::new(
+            Duration::from_millis(50),
+            &mut dispenser_loop,
+            &DISPENSER_LOOP_METRICS,
+        ),
+        Periodic::new(
+            Duration::from_secs(5),
+            &mut metrics_loop,
+            &METRICS_LOOP_METRICS,
+        ),
+    ]);
+
+    let ui = async {
+        loop {
+            let event = buttons.wait_for_button_state_change().await;
+            let new_state = event.new_state().state();
+            let source = event.new_state().source();
+            info!("Button {:?} is now in state {:?}", &source, new_state);
+
+            match source {
+                buttons::ButtonKind::OneCup => {
+                    if new_state == ButtonState::Pressed {
+                        leds.set_state(
+                            leds::LEDKind::OneCup,
+                            leds::LEDState::Blinking(Hertz::new(2).unwrap()),
+                        );
+                        let (dispenser, pump) = &mut *dispenser.borrow_mut();
+                        dispenser.start(
+                            pump,
+                            &flow_meter,
+                            pump::PumpPower::Fraction(Percent::new(50).unwrap()),
+                            Milligrams::new(100000),
+                        );
                     }
-                    buttons::ButtonKind::HotWater => {
-                        pid.set_target_temperature(0);
+                }
+                buttons::ButtonKind::TwoCup => {
+                    leds.set_state(
+                        leds::LEDKind::TwoCup,
+                        leds::LEDState::Blinking(Hertz::new(3).unwrap()),
+                    );
+                    if new_state == ButtonState::Pressed {
+                        leds.set_state(
+                            leds::LEDKind::OneCup,
+                            leds::LEDState::Blinking(Hertz::new(2).unwrap()),
+                        );
+                        let (dispenser, pump) = &mut *dispenser.borrow_mut();
+                        dispenser.start(
+                            pump,
+                            &flow_meter,
+                            pump::PumpPower::Fraction(Percent::MAX),
+                            Milligrams::new(100000),
+                        );
                     }
-                    _ => (),
                 }
-            }
-            embassy_futures::select::Either::Second(_) => {
-                let temperature = temperatur.temperature_in_c();
-                info!("temperatur={}°C", temperature);
-                let next_value = pid.update(temperature);
-                info!("pid_next_power_value={}", next_value);
-                heater.set_power(next_value);
-                if flow_meter.flowed_mg() - start_flowed_value > 100000 {
-                    pump.disable();
+                buttons::ButtonKind::Steam => {
+                    pid.borrow_mut()
+                        .set_target_temperature(MilliCelsius::from_celsius(0).unwrap());
+                }
+                buttons::ButtonKind::HotWater => {
+                    pid.borrow_mut()
+                        .set_target_temperature(MilliCelsius::from_celsius(0).unwrap());
                 }
             }
         }
-    }
+    };
+
+    let (never, _) = join(control_loop.run(), ui).await;
+    never
 }
@@ -3,18 +3,15 @@
 
 // use core::num::NonZeroU16;
 
-use bambino_fw::hardware::{buttons, pump};
+use bambino_fw::hardware::{board::Board, pump};
 use embassy_executor::Spawner;
 use embassy_time::Timer;
 use {defmt_rtt as _, panic_probe as _};
 
-use defmt::*;
-
 #[embassy_executor::main]
-async fn main(mut spawner: Spawner) -> ! {
-    let p = embassy_stm32::init(Default::default());
-    let mut pump = unsafe { pump::Pump::new() };
-    let mut buttons = unsafe { buttons::Buttons::new(&mut spawner) };
+async fn main(spawner: Spawner) -> ! {
+    let Board { mut pump, .. } =
+        Board::new(embassy_stm32::init(Default::default()), spawner).unwrap();
 
     pump.set_power(pump::PumpPower::Lowest);
     pump.enable();
@@ -0,0 +1,152 @@
+#![no_std]
+#![no_main]
+
+//!
+//! Records a multi-point calibration of the NTC and persists it in the flash.
+//!
+//! The thermoblock is heated to each of the `SETPOINTS` in turn. The one cup LED blinks while
+//! the temperature settles and turns on once it is stable. Then, measure the temperature with a
+//! reference thermometer and enter it as offset to the temperature printed via the debug link:
+//! - one cup: increase the reference temperature by 0.1 °C,
+//! - two cups: decrease the reference temperature by 0.1 °C,
+//! - steam: record the point and continue with the next setpoint,
+//! - hot water: persist the recorded points and turn the heater off. If no point was recorded,
+//!   the persisted calibration is removed instead, i.e., the NTC model is used as is.
+//!
+//! The calibration is applied after the next restart.
+//!
+
+use core::cell::{Cell, RefCell};
+
+use bambino_fw::{
+    hardware::{
+        board::Board,
+        buttons::{ButtonKind, ButtonState},
+        calibration::{CalibrationPoint, CalibrationTable},
+        leds::{LEDKind, LEDState},
+    },
+    logic::temperature_pid::TemperaturePID,
+    units::{Hertz, MilliCelsius},
+};
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_time::{Duration, Ticker};
+use {defmt_rtt as _, panic_probe as _};
+
+/// The temperatures in °C the points are recorded at.
+const SETPOINTS: [i32; 4] = [40, 60, 80, 93];
+/// The step the reference temperature is adjusted by per button press in m°C.
+const REFERENCE_STEP: i32 = 100;
+/// The temperature is considered stable within this distance to the setpoint in m°C...
+const SETTLED_DISTANCE: i32 = 500;
+/// ... and while it changes slower than this rate in °C/s.
+const SETTLED_RATE: f32 = 0.05;
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) -> ! {
+    let Board {
+        mut buttons,
+        mut calibration,
+        mut heater,
+        leds,
+        mut temperature,
+        ..
+    } = Board::new(embassy_stm32::init(Default::default()), spawner).unwrap();
+
+    // The points are recorded against the uncorrected NTC model.
+    temperature.set_calibration(CalibrationTable::EMPTY);
+
+    let leds = RefCell::new(leds);
+    let pid = RefCell::new(TemperaturePID::new());
+    let setpoint = Cell::new(Some(0usize));
+    let target = |index: usize| MilliCelsius::from_celsius(SETPOINTS[index]).unwrap();
+    pid.borrow_mut().set_target_temperature(target(0));
+
+    let thermal_loop = async {
+        let period = Duration::from_millis(50);
+        let mut ticker = Ticker::every(period);
+        let mut led_state = LEDState::Off;
+        loop {
+            ticker.next().await;
+            pid.borrow_mut().control(&temperature, &mut heater, period);
+
+            let new_led_state = match setpoint.get() {
+                Some(index) => {
+                    let distance = (temperature.temperature().get() - target(index).get()).abs();
+                    let rate = temperature.rate().get().abs();
+                    if distance <= SETTLED_DISTANCE && rate <= SETTLED_RATE {
+                        LEDState::On
+                    } else {
+                        LEDState::Blinking(Hertz::new(2).unwrap())
+                    }
+                }
+                None => LEDState::Off,
+            };
+            if new_led_state != led_state {
+                led_state = new_led_state;
+                leds.borrow_mut().set_state(LEDKind::OneCup, led_state);
+            }
+        }
+    };
+
+    let ui = async {
+        let mut table = CalibrationTable::EMPTY;
+        let mut offset = 0;
+        loop {
+            let event = buttons.wait_for_button_state_change().await;
+            if event.new_state().state() != ButtonState::Pressed {
+                continue;
+            }
+            let Some(index) = setpoint.get() else {
+                continue;
+            };
+
+            match event.new_state().source() {
+                ButtonKind::OneCup => offset += REFERENCE_STEP,
+                ButtonKind::TwoCup => offset -= REFERENCE_STEP,
+                ButtonKind::Steam => {
+                    let point = CalibrationPoint {
+                        raw: temperature.raw_temperature() as f32,
+                        reference: MilliCelsius::new(temperature.temperature().get() + offset),
+                    };
+                    match table.insert(point) {
+                        Ok(()) => info!("Recorded {}", point),
+                        Err(error) => warn!("Discarded {}: {}", point, error),
+                    }
+                    offset = 0;
+
+                    let next = (index + 1) % SETPOINTS.len();
+                    setpoint.set(Some(next));
+                    pid.borrow_mut().set_target_temperature(target(next));
+                }
+                ButtonKind::HotWater => {
+                    setpoint.set(None);
+                    pid.borrow_mut()
+                        .set_target_temperature(MilliCelsius::new(0));
+                    let result = if table.is_empty() {
+                        calibration.clear()
+                    } else {
+                        calibration.store(&table)
+                    };
+                    match result {
+                        Ok(()) => info!("Persisted {} points", table.points().len()),
+                        Err(error) => error!("Failed to persist the calibration: {}", error),
+                    }
+                    leds.borrow_mut().set_state(LEDKind::TwoCup, LEDState::On);
+                    continue;
+                }
+            }
+            info!(
+                "setpoint={}°C temperature={}°C reference={}°C raw={}",
+                SETPOINTS[setpoint.get().unwrap_or(index)],
+                temperature.temperature().as_celsius_f32(),
+                (temperature.temperature().get() + offset) as f32 / 1000.0,
+                temperature.raw_temperature()
+            );
+        }
+    };
+
+    let (never, _) = join(thermal_loop, ui).await;
+    never
+}
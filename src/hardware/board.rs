@@ -0,0 +1,173 @@
+//!
+//! The `Board` owns all peripherals of the machine and hands out the drivers
+//! of the different hardware components.
+//!
+
+use embassy_executor::Spawner;
+use embassy_futures::join;
+use embassy_stm32::Peripherals;
+use embassy_time::Duration;
+
+use super::{
+    buttons::{Buttons, ButtonsRunner},
+    calibration::CalibrationStorage,
+    config::{BoardPeripherals, PumpTimer},
+    error::DriverError,
+    flow_meter::{FlowMeter, FlowMeterRunner},
+    heater::{Heater, HeaterRunner},
+    leds::{LEDs, LEDsRunner},
+    pump::Pump,
+    solenoid::Solenoid,
+    telemetry::{Telemetry, TelemetryRunner},
+    temperature::{Temperature, TemperatureRunner},
+};
+
+/// The interval the telemetry snapshots are collected at.
+const TELEMETRY_PERIOD: Duration = Duration::from_millis(100);
+
+/// All hardware components of the machine.
+///
+/// Since `Board::new` consumes the `Peripherals` returned by `embassy_stm32::init`,
+/// each driver can only be constructed once. The pins the drivers use are taken from
+/// the pin map of the selected board (see [`super::config`]).
+pub struct Board {
+    /// The one cup, two cups, steam and hot water buttons.
+    pub buttons: Buttons,
+    /// The calibration of the NTC persisted in flash. It is applied to `temperature` on
+    /// creation of the board.
+    pub calibration: CalibrationStorage,
+    /// The flow meter.
+    pub flow_meter: FlowMeter<'static>,
+    /// The heater.
+    pub heater: Heater,
+    /// The LEDs of the one cup and two cups buttons.
+    pub leds: LEDs,
+    /// The pump driven via PWM.
+    pub pump: Pump<'static, PumpTimer>,
+    /// The solenoid switching between shower and steam wand.
+    pub solenoid: Solenoid<'static>,
+    /// Snapshots of all components, collected every 100 ms.
+    pub telemetry: Telemetry,
+    /// The NTC sampled via the ADC.
+    pub temperature: Temperature,
+}
+
+impl Board {
+    /// Create all drivers from the peripherals `p` and spawn their tasks on `spawner`.
+    pub fn new(p: Peripherals, spawner: Spawner) -> Result<Self, DriverError> {
+        let p = BoardPeripherals::take(p);
+        let buttons = Buttons::new(
+            &spawner,
+            p.one_cup_button,
+            p.two_cup_button,
+            p.steam_button,
+            p.hot_water_button,
+        )?;
+        let flow_meter =
+            FlowMeter::new(&spawner, p.flow_signal, p.flow_signal_exti, p.flow_enable)?;
+        let heater = Heater::new(&spawner, p.heater)?;
+        let leds = LEDs::new(&spawner, p.one_cup_led, p.two_cup_led)?;
+        let pump = Pump::new(p.pump, p.pump_timer);
+        let solenoid = Solenoid::new(p.solenoid);
+        let telemetry = Telemetry::new(&spawner, TELEMETRY_PERIOD)?;
+        let mut temperature = Temperature::new(&spawner, p.ntc_adc, p.ntc)?;
+        let mut calibration = CalibrationStorage::new(p.calibration_flash);
+        if let Some(table) = calibration.load() {
+            temperature.set_calibration(table);
+        }
+
+        Ok(Board {
+            buttons,
+            calibration,
+            flow_meter,
+            heater,
+            leds,
+            pump,
+            solenoid,
+            telemetry,
+            temperature,
+        })
+    }
+
+    /// Create all drivers from the peripherals `p` without spawning any task. The drivers
+    /// only work while the returned runner is running.
+    pub fn new_with_runner(p: Peripherals) -> Result<(Self, BoardRunner), DriverError> {
+        let p = BoardPeripherals::take(p);
+        let (buttons, buttons_runner) = Buttons::new_with_runner(
+            p.one_cup_button,
+            p.two_cup_button,
+            p.steam_button,
+            p.hot_water_button,
+        );
+        let (flow_meter, flow_meter_runner) =
+            FlowMeter::new_with_runner(p.flow_signal, p.flow_signal_exti, p.flow_enable);
+        let (heater, heater_runner) = Heater::new_with_runner(p.heater);
+        let (leds, leds_runner) = LEDs::new_with_runner(p.one_cup_led, p.two_cup_led);
+        let pump = Pump::new(p.pump, p.pump_timer);
+        let solenoid = Solenoid::new(p.solenoid);
+        let (telemetry, telemetry_runner) = Telemetry::new_with_runner(TELEMETRY_PERIOD);
+        let (mut temperature, temperature_runner) = Temperature::new_with_runner(p.ntc_adc, p.ntc);
+        let mut calibration = CalibrationStorage::new(p.calibration_flash);
+        if let Some(table) = calibration.load() {
+            temperature.set_calibration(table);
+        }
+
+        let board = Board {
+            buttons,
+            calibration,
+            flow_meter,
+            heater,
+            leds,
+            pump,
+            solenoid,
+            telemetry,
+            temperature,
+        };
+        let runner = BoardRunner {
+            buttons: buttons_runner,
+            flow_meter: flow_meter_runner,
+            heater: heater_runner,
+            leds: leds_runner,
+            telemetry: telemetry_runner,
+            temperature: temperature_runner,
+        };
+        Ok((board, runner))
+    }
+}
+
+/// The runners of all drivers of a `Board` created via `Board::new_with_runner`.
+///
+/// The runners can either be run all together via `run()` or individually, e.g.,
+/// in order to run the heater on a higher priority executor.
+pub struct BoardRunner {
+    /// The runner of `Board::buttons`.
+    pub buttons: ButtonsRunner,
+    /// The runner of `Board::flow_meter`.
+    pub flow_meter: FlowMeterRunner<'static>,
+    /// The runner of `Board::heater`.
+    pub heater: HeaterRunner,
+    /// The runner of `Board::leds`.
+    pub leds: LEDsRunner,
+    /// The runner of `Board::telemetry`.
+    pub telemetry: TelemetryRunner,
+    /// The runner of `Board::temperature`.
+    pub temperature: TemperatureRunner,
+}
+
+impl BoardRunner {
+    /// Run all drivers of the board.
+    pub async fn run(self) -> ! {
+        let ((never, ..), _) = join::join(
+            join::join5(
+                self.buttons.run(),
+                self.flow_meter.run(),
+                self.heater.run(),
+                self.leds.run(),
+                self.temperature.run(),
+            ),
+            self.telemetry.run(),
+        )
+        .await;
+        never
+    }
+}
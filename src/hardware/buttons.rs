@@ -1,25 +1,59 @@
 //!
 //! Everything related to the button of the machine.
 //!
+#[cfg(feature = "stm32")]
 use embassy_executor::Spawner;
+#[cfg(feature = "stm32")]
 use embassy_futures::select::{self};
+#[cfg(feature = "stm32")]
 use embassy_stm32::{
-    exti::{Channel as _, ExtiInput},
+    exti::{AnyChannel, Channel as _, ExtiInput},
     gpio::{self, AnyPin, Pin},
-    Peripheral, Peripherals,
+};
+#[cfg(feature = "stm32")]
+use {
+    super::telemetry::ButtonStates,
+    core::cell::Cell,
+    defmt::warn,
+    embassy_sync::{
+        blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+        channel::Channel,
+    },
 };
 
+#[cfg(feature = "stm32")]
 use futures::FutureExt;
 
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+#[cfg(feature = "stm32")]
+use super::{
+    config::{
+        HotWaterButtonExti, HotWaterButtonPin, OneCupButtonExti, OneCupButtonPin, SteamButtonExti,
+        SteamButtonPin, TwoCupButtonExti, TwoCupButtonPin,
+    },
+    error::DriverError,
+    events::{self, MachineEvent},
+};
 use embassy_time::{Duration, Instant};
 
-type ButtonEventChannel =
-    embassy_sync::channel::Channel<ThreadModeRawMutex, ButtonStateTransitionEvent, 6>;
-static BUTTON_EVENT_CHANNEL: ButtonEventChannel = ButtonEventChannel::new();
 const DEBOUNCE_INTERVAL: Duration = Duration::from_millis(50);
+/// Number of button state transitions that are buffered for `Buttons`.
+#[cfg(feature = "stm32")]
+const BUTTON_EVENT_CAPACITY: usize = 8;
+
+/// The button state transitions for `Buttons`. They are kept apart from the machine event bus,
+/// such that the frequent flow and temperature events cannot push them out of the queue.
+#[cfg(feature = "stm32")]
+static BUTTON_EVENTS: ButtonEventChannel = ButtonEventChannel::new();
+#[cfg(feature = "stm32")]
+type ButtonEventChannel =
+    Channel<CriticalSectionRawMutex, ButtonStateTransitionEvent, BUTTON_EVENT_CAPACITY>;
 
-#[derive(defmt::Format, Clone, Copy)]
+#[cfg(feature = "stm32")]
+static BUTTON_STATES: Mutex<CriticalSectionRawMutex, Cell<ButtonStates>> =
+    Mutex::new(Cell::new(ButtonStates::RELEASED));
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// The kind of button that may be pressed or released.
 pub enum ButtonKind {
     /// The one cup button.
@@ -34,7 +68,8 @@ pub enum ButtonKind {
 
 /// Event emitted if a button changes its state from pressed to released or
 /// the other way around.
-#[derive(Clone, Copy, defmt::Format)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct ButtonEvent {
     /// The button that was pressed.
     source: ButtonKind,
@@ -54,10 +89,9 @@ impl ButtonEvent {
         }
     }
 
-    /// Construct a new `ButtonEvent` with inverted button state and timestamp
-    /// set to `Instant::now()`.
-    fn state_transition(self) -> ButtonEvent {
-        ButtonEvent::new(self.source, self.state.not(), Instant::now())
+    /// Construct a new `ButtonEvent` with inverted button state and the given `timestamp`.
+    fn state_transition(self, timestamp: Instant) -> ButtonEvent {
+        ButtonEvent::new(self.source, self.state.not(), timestamp)
     }
 
     /// The time elapsed since recording this event.
@@ -82,7 +116,8 @@ impl ButtonEvent {
 }
 
 /// State of a button. Ether `Pressed` or `Released`.
-#[derive(Clone, Copy, defmt::Format, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ButtonState {
     /// The button is pressed.
     Pressed,
@@ -100,7 +135,8 @@ impl ButtonState {
 }
 
 /// Event emmited if a button changes from pressed to released or the other way around.
-#[derive(defmt::Format)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct ButtonStateTransitionEvent {
     old_state: ButtonEvent,
     new_state: ButtonEvent,
@@ -120,170 +156,278 @@ impl ButtonStateTransitionEvent {
     }
 }
 
+/// Debounces the state transitions of a single button by ignoring all transitions
+/// that happen within `DEBOUNCE_INTERVAL` after the last accepted transition.
+pub struct Debouncer {
+    last_event: ButtonEvent,
+}
+
+impl Debouncer {
+    /// Create a new `Debouncer` for the button `source` that is released at `timestamp`.
+    pub fn new(source: ButtonKind, timestamp: Instant) -> Self {
+        Debouncer {
+            last_event: ButtonEvent::new(source, ButtonState::Released, timestamp),
+        }
+    }
+
+    /// The last accepted event of the button.
+    pub fn last_event(&self) -> &ButtonEvent {
+        &self.last_event
+    }
+
+    /// Register that the button changed its state at `timestamp`. Returns the transition
+    /// if it is accepted, or `None` if it is considered to be a bounce.
+    pub fn on_state_change(&mut self, timestamp: Instant) -> Option<ButtonStateTransitionEvent> {
+        let elapsed_since_last_event =
+            timestamp.saturating_duration_since(self.last_event.timestamp);
+        if elapsed_since_last_event < DEBOUNCE_INTERVAL {
+            return None;
+        }
+
+        let new_state = self.last_event.state_transition(timestamp);
+        let old_state = core::mem::replace(&mut self.last_event, new_state);
+        Some(ButtonStateTransitionEvent {
+            old_state,
+            new_state,
+        })
+    }
+}
+
 /// All buttons of the machine.
-pub struct Buttons;
+#[cfg(feature = "stm32")]
+pub struct Buttons {
+    _private: (),
+}
 
+#[cfg(feature = "stm32")]
 impl Buttons {
-    /// Create a new `Buttons` instance.
-    ///
-    /// # Panics
-    /// If the spawner fails to spawn the task.
-    /// # Safety
-    /// This is only safe when called once and without concurrently calling any of the `new()``
-    /// methods of the other hardware components.
-    pub unsafe fn new(spawner: &mut Spawner) -> Self {
-        spawner.spawn(button_task()).unwrap();
-        Buttons {}
+    /// Create a new `Buttons` instance that owns the pins and EXTI channels of the buttons
+    /// and spawn the task watching the buttons on `spawner`.
+    pub fn new(
+        spawner: &Spawner,
+        one_cup: (OneCupButtonPin, OneCupButtonExti),
+        two_cup: (TwoCupButtonPin, TwoCupButtonExti),
+        steam: (SteamButtonPin, SteamButtonExti),
+        hot_water: (HotWaterButtonPin, HotWaterButtonExti),
+    ) -> Result<Self, DriverError> {
+        let (buttons, runner) = Buttons::new_with_runner(one_cup, two_cup, steam, hot_water);
+        spawner.spawn(button_task(runner))?;
+        Ok(buttons)
+    }
+
+    /// Create a new `Buttons` instance that owns the pins and EXTI channels of the buttons
+    /// without spawning a task. Button state changes are only detected while the returned
+    /// runner is running.
+    pub fn new_with_runner(
+        one_cup: (OneCupButtonPin, OneCupButtonExti),
+        two_cup: (TwoCupButtonPin, TwoCupButtonExti),
+        steam: (SteamButtonPin, SteamButtonExti),
+        hot_water: (HotWaterButtonPin, HotWaterButtonExti),
+    ) -> (Self, ButtonsRunner) {
+        let runner = ButtonsRunner {
+            one_cup: exti_input(one_cup.0.degrade(), one_cup.1.degrade()),
+            two_cup: exti_input(two_cup.0.degrade(), two_cup.1.degrade()),
+            steam: exti_input(steam.0.degrade(), steam.1.degrade()),
+            hot_water: exti_input(hot_water.0.degrade(), hot_water.1.degrade()),
+        };
+        (Buttons { _private: () }, runner)
     }
 
     /// Wait for any button to change its state from pressed to released or released
     /// to pressed. Button pressen are queued up, such that this function must not
-    /// be awaited the whole time. However, the queue buffer is limited, such that
-    /// this function should be called preiodically in order to avoid state transitions
-    /// to be lost.
+    /// be awaited the whole time. However, the queue buffer is limited to
+    /// `BUTTON_EVENT_CAPACITY` transitions, such that this function should be called
+    /// preiodically. If the queue is full, the oldest transition is dropped.
     pub async fn wait_for_button_state_change(&mut self) -> ButtonStateTransitionEvent {
-        BUTTON_EVENT_CHANNEL.receive().await
+        BUTTON_EVENTS.receive().await
     }
 }
 
+#[cfg(feature = "stm32")]
 struct ButtonsTask<'a> {
     one_cup_exti: ExtiInput<'a, AnyPin>,
     two_cup_exti: ExtiInput<'a, AnyPin>,
     steam_exti: ExtiInput<'a, AnyPin>,
     hot_water_exti: ExtiInput<'a, AnyPin>,
-    last_one_cup_event: ButtonEvent,
-    last_two_cup_event: ButtonEvent,
-    last_hot_water_event: ButtonEvent,
-    last_steam_event: ButtonEvent,
+    one_cup: Debouncer,
+    two_cup: Debouncer,
+    hot_water: Debouncer,
+    steam: Debouncer,
 }
 
+#[cfg(feature = "stm32")]
 impl<'a> ButtonsTask<'a> {
-    fn new(p: Peripherals) -> Self {
-        let one_cup_input = gpio::Input::new(
-            unsafe { p.PA0.clone_unchecked().degrade() },
-            gpio::Pull::None,
-        );
-        let one_cup_exti = ExtiInput::new(one_cup_input, unsafe {
-            p.EXTI0.clone_unchecked().degrade()
-        });
-
-        let two_cup_input = gpio::Input::new(
-            unsafe { p.PA1.clone_unchecked().degrade() },
-            gpio::Pull::None,
-        );
-        let two_cup_exti = ExtiInput::new(two_cup_input, unsafe {
-            p.EXTI1.clone_unchecked().degrade()
-        });
-
-        let steam_input = gpio::Input::new(
-            unsafe { p.PA2.clone_unchecked().degrade() },
-            gpio::Pull::None,
-        );
-        let steam_exti =
-            ExtiInput::new(steam_input, unsafe { p.EXTI2.clone_unchecked().degrade() });
-
-        let hot_water_input = gpio::Input::new(
-            unsafe { p.PA3.clone_unchecked().degrade() },
-            gpio::Pull::None,
-        );
-        let hot_water_exti = ExtiInput::new(hot_water_input, unsafe {
-            p.EXTI3.clone_unchecked().degrade()
-        });
-
-        let last_one_cup_event =
-            ButtonEvent::new(ButtonKind::OneCup, ButtonState::Released, Instant::now());
-        let last_two_cup_event =
-            ButtonEvent::new(ButtonKind::TwoCup, ButtonState::Released, Instant::now());
-        let last_hot_water_event =
-            ButtonEvent::new(ButtonKind::HotWater, ButtonState::Released, Instant::now());
-        let last_steam_event =
-            ButtonEvent::new(ButtonKind::Steam, ButtonState::Released, Instant::now());
-
+    fn new(
+        one_cup_exti: ExtiInput<'a, AnyPin>,
+        two_cup_exti: ExtiInput<'a, AnyPin>,
+        steam_exti: ExtiInput<'a, AnyPin>,
+        hot_water_exti: ExtiInput<'a, AnyPin>,
+    ) -> Self {
+        let now = Instant::now();
         ButtonsTask {
             one_cup_exti,
             two_cup_exti,
             hot_water_exti,
             steam_exti,
-            last_one_cup_event,
-            last_two_cup_event,
-            last_hot_water_event,
-            last_steam_event,
+            one_cup: Debouncer::new(ButtonKind::OneCup, now),
+            two_cup: Debouncer::new(ButtonKind::TwoCup, now),
+            hot_water: Debouncer::new(ButtonKind::HotWater, now),
+            steam: Debouncer::new(ButtonKind::Steam, now),
         }
     }
 
-    fn kind_to_last_event(&self, kind: ButtonKind) -> &ButtonEvent {
+    fn kind_to_debouncer_mut(&mut self, kind: ButtonKind) -> &mut Debouncer {
         match kind {
-            ButtonKind::OneCup => &self.last_one_cup_event,
-            ButtonKind::TwoCup => &self.last_two_cup_event,
-            ButtonKind::HotWater => &self.last_hot_water_event,
-            ButtonKind::Steam => &self.last_steam_event,
+            ButtonKind::OneCup => &mut self.one_cup,
+            ButtonKind::TwoCup => &mut self.two_cup,
+            ButtonKind::HotWater => &mut self.hot_water,
+            ButtonKind::Steam => &mut self.steam,
         }
     }
 
-    fn kind_to_last_event_mut(&mut self, kind: ButtonKind) -> &mut ButtonEvent {
-        match kind {
-            ButtonKind::OneCup => &mut self.last_one_cup_event,
-            ButtonKind::TwoCup => &mut self.last_two_cup_event,
-            ButtonKind::HotWater => &mut self.last_hot_water_event,
-            ButtonKind::Steam => &mut self.last_steam_event,
-        }
-    }
-
-    async fn wait_for_button_event(&mut self) -> ButtonEvent {
-        let one_cup_watch = match self.last_one_cup_event.state {
+    async fn wait_for_button_event(&mut self) -> ButtonKind {
+        let one_cup_watch = match self.one_cup.last_event().state {
             ButtonState::Pressed => self.one_cup_exti.wait_for_low().left_future(),
             ButtonState::Released => self.one_cup_exti.wait_for_high().right_future(),
         };
-        let two_cup_watch = match self.last_two_cup_event.state {
+        let two_cup_watch = match self.two_cup.last_event().state {
             ButtonState::Pressed => self.two_cup_exti.wait_for_low().left_future(),
             ButtonState::Released => self.two_cup_exti.wait_for_high().right_future(),
         };
-        let hot_water_watch = match self.last_hot_water_event.state {
+        let hot_water_watch = match self.hot_water.last_event().state {
             ButtonState::Pressed => self.hot_water_exti.wait_for_low().left_future(),
             ButtonState::Released => self.hot_water_exti.wait_for_high().right_future(),
         };
-        let steam_watch = match self.last_steam_event.state {
+        let steam_watch = match self.steam.last_event().state {
             ButtonState::Pressed => self.steam_exti.wait_for_low().left_future(),
             ButtonState::Released => self.steam_exti.wait_for_high().right_future(),
         };
 
         match select::select4(one_cup_watch, two_cup_watch, hot_water_watch, steam_watch).await {
-            select::Either4::First(_) => self.last_one_cup_event.state_transition(),
-            select::Either4::Second(_) => self.last_two_cup_event.state_transition(),
-            select::Either4::Third(_) => self.last_hot_water_event.state_transition(),
-            select::Either4::Fourth(_) => self.last_steam_event.state_transition(),
+            select::Either4::First(_) => ButtonKind::OneCup,
+            select::Either4::Second(_) => ButtonKind::TwoCup,
+            select::Either4::Third(_) => ButtonKind::HotWater,
+            select::Either4::Fourth(_) => ButtonKind::Steam,
         }
     }
 
-    async fn wait_for_button_event_debounced(&mut self) -> (ButtonEvent, ButtonEvent) {
+    async fn wait_for_button_event_debounced(&mut self) -> ButtonStateTransitionEvent {
         loop {
-            let event @ ButtonEvent { source, .. } = self.wait_for_button_event().await;
+            let source = self.wait_for_button_event().await;
+            if let Some(event) = self
+                .kind_to_debouncer_mut(source)
+                .on_state_change(Instant::now())
             {
-                let elapsed_since_last_event = self.kind_to_last_event(source).elapsed();
-                if elapsed_since_last_event < DEBOUNCE_INTERVAL {
-                    continue;
-                }
-                let old_event = self.kind_to_last_event_mut(source);
-                let old_event_copy = *old_event;
-                *old_event = event;
-                return (old_event_copy, event);
+                return event;
             }
         }
     }
 }
 
+#[cfg(feature = "stm32")]
+fn exti_input<'a>(pin: AnyPin, channel: AnyChannel) -> ExtiInput<'a, AnyPin> {
+    ExtiInput::new(gpio::Input::new(pin, gpio::Pull::None), channel)
+}
+
+/// The debounced state of all buttons.
+#[cfg(feature = "stm32")]
+pub(crate) fn button_states() -> ButtonStates {
+    BUTTON_STATES.lock(|states| states.get())
+}
+
+/// Watches the buttons of a `Buttons` instance created via `Buttons::new_with_runner`.
+#[cfg(feature = "stm32")]
+pub struct ButtonsRunner {
+    one_cup: ExtiInput<'static, AnyPin>,
+    two_cup: ExtiInput<'static, AnyPin>,
+    steam: ExtiInput<'static, AnyPin>,
+    hot_water: ExtiInput<'static, AnyPin>,
+}
+
+#[cfg(feature = "stm32")]
+impl ButtonsRunner {
+    /// Watch the buttons and publish their debounced state transitions.
+    pub async fn run(self) -> ! {
+        let mut buttons = ButtonsTask::new(self.one_cup, self.two_cup, self.steam, self.hot_water);
+
+        loop {
+            let event = buttons.wait_for_button_event_debounced().await;
+            BUTTON_STATES.lock(|states| {
+                let mut new_states = states.get();
+                new_states.set(event.new_state().source(), event.new_state().state());
+                states.set(new_states);
+            });
+            events::publish(MachineEvent::Button(event));
+            if BUTTON_EVENTS.try_send(event).is_err() {
+                warn!("Button event queue is full, dropping the oldest event");
+                let _ = BUTTON_EVENTS.try_receive();
+                let _ = BUTTON_EVENTS.try_send(event);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "stm32")]
 #[embassy_executor::task]
-async fn button_task() -> ! {
-    let p = unsafe { Peripherals::steal() };
-    let mut buttons = ButtonsTask::new(p);
+async fn button_task(runner: ButtonsRunner) -> ! {
+    runner.run().await
+}
 
-    loop {
-        let (old_state, new_state) = buttons.wait_for_button_event_debounced().await;
-        let event = ButtonStateTransitionEvent {
-            old_state,
-            new_state,
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at_ms(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn accepts_transition_after_debounce_interval() {
+        let mut debouncer = Debouncer::new(ButtonKind::OneCup, at_ms(0));
+
+        let event = debouncer.on_state_change(at_ms(100)).unwrap();
+        assert_eq!(event.old_state().state(), ButtonState::Released);
+        assert_eq!(event.new_state().state(), ButtonState::Pressed);
+        assert_eq!(event.new_state().source(), ButtonKind::OneCup);
+        assert_eq!(event.new_state().timestamp(), at_ms(100));
+        assert_eq!(event.old_state().timestamp(), at_ms(0));
+    }
 
-        BUTTON_EVENT_CHANNEL.send(event).await;
+    #[test]
+    fn ignores_bounces() {
+        let mut debouncer = Debouncer::new(ButtonKind::Steam, at_ms(0));
+        assert!(debouncer.on_state_change(at_ms(100)).is_some());
+
+        assert!(debouncer.on_state_change(at_ms(110)).is_none());
+        assert!(debouncer.on_state_change(at_ms(149)).is_none());
+        assert_eq!(debouncer.last_event().state(), ButtonState::Pressed);
+
+        let event = debouncer.on_state_change(at_ms(150)).unwrap();
+        assert_eq!(event.new_state().state(), ButtonState::Released);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn accepted_transitions_alternate_and_are_spaced(
+            deltas in proptest::collection::vec(0u64..200, 1..64)
+        ) {
+            let mut debouncer = Debouncer::new(ButtonKind::TwoCup, at_ms(0));
+            let mut now = 0;
+            for delta in deltas {
+                now += delta;
+                let before = *debouncer.last_event();
+                match debouncer.on_state_change(at_ms(now)) {
+                    Some(event) => {
+                        proptest::prop_assert!(now - before.timestamp().as_millis() >= 50);
+                        proptest::prop_assert_eq!(*event.old_state(), before);
+                        proptest::prop_assert_ne!(event.new_state().state(), before.state());
+                    }
+                    None => {
+                        proptest::prop_assert!(now - before.timestamp().as_millis() < 50);
+                        proptest::prop_assert_eq!(*debouncer.last_event(), before);
+                    }
+                }
+            }
+        }
     }
 }
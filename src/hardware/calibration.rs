@@ -0,0 +1,327 @@
+//!
+//! Multi-point calibration of the NTC. The calibration table records the temperature measured
+//! by a reference thermometer against the raw readings of the NTC and corrects the temperature
+//! converted via the NTC model by linearly interpolating between the points.
+//!
+//! The table is persisted in the last page of the flash, which is excluded from the firmware
+//! image via `memory.x`. Without a table, the NTC model of the board profile is used as is.
+//!
+
+use super::temperature::raw_into_milli_celsius;
+use crate::units::MilliCelsius;
+#[cfg(feature = "stm32")]
+use {
+    super::config::CalibrationFlash,
+    embassy_stm32::flash::{self, Blocking, Flash, FLASH_SIZE},
+};
+
+/// The maximal number of points of a calibration table.
+pub const MAX_CALIBRATION_POINTS: usize = 8;
+/// The size of a serialized calibration table in bytes.
+pub const CALIBRATION_RECORD_SIZE: usize = 12 + 8 * MAX_CALIBRATION_POINTS;
+/// Marks a serialized calibration table.
+const CALIBRATION_MAGIC: u32 = 0x4E54_4331;
+/// The size of the flash page the calibration table is stored in.
+#[cfg(feature = "stm32")]
+const CALIBRATION_PAGE_SIZE: u32 = 2048;
+/// The offset of the flash page the calibration table is stored in, i.e., the last page.
+#[cfg(feature = "stm32")]
+const CALIBRATION_PAGE_OFFSET: u32 = FLASH_SIZE as u32 - CALIBRATION_PAGE_SIZE;
+
+/// A temperature measured by a reference thermometer together with the raw reading of the NTC.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CalibrationPoint {
+    /// The raw reading of the NTC in ADC counts.
+    pub raw: f32,
+    /// The temperature measured by the reference thermometer.
+    pub reference: MilliCelsius,
+}
+
+/// An error that occurred while adding a point to a `CalibrationTable`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CalibrationError {
+    /// The table already holds `MAX_CALIBRATION_POINTS` points.
+    TableFull,
+    /// The point contradicts the other points of the table, i.e., the temperature would no
+    /// longer change monotonically with the readings.
+    NotMonotonic,
+}
+
+/// A piecewise-linear correction of the NTC model, see the module documentation.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CalibrationTable {
+    /// The points, sorted by the raw reading.
+    points: [CalibrationPoint; MAX_CALIBRATION_POINTS],
+    /// The difference between the reference and the NTC model in m°C at each point.
+    corrections: [i32; MAX_CALIBRATION_POINTS],
+    len: u8,
+}
+
+impl CalibrationTable {
+    /// A table without points, i.e., the NTC model is used without correction.
+    pub const EMPTY: CalibrationTable = CalibrationTable {
+        points: [CalibrationPoint {
+            raw: 0.0,
+            reference: MilliCelsius::new(0),
+        }; MAX_CALIBRATION_POINTS],
+        corrections: [0; MAX_CALIBRATION_POINTS],
+        len: 0,
+    };
+
+    /// The points of the table, sorted by the raw reading.
+    pub fn points(&self) -> &[CalibrationPoint] {
+        &self.points[..self.len as usize]
+    }
+
+    /// Whether the table has no points.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add `point` to the table. The table is left unchanged if the point cannot be added.
+    pub fn insert(&mut self, point: CalibrationPoint) -> Result<(), CalibrationError> {
+        let len = self.len as usize;
+        if len >= MAX_CALIBRATION_POINTS {
+            return Err(CalibrationError::TableFull);
+        }
+        if !point.raw.is_finite() {
+            return Err(CalibrationError::NotMonotonic);
+        }
+
+        let mut table = *self;
+        let index = self.points().iter().filter(|p| p.raw < point.raw).count();
+        table.points.copy_within(index..len, index + 1);
+        table.corrections.copy_within(index..len, index + 1);
+        table.points[index] = point;
+        table.corrections[index] = point.reference.get() - raw_into_milli_celsius(point.raw).get();
+        table.len += 1;
+
+        // The corrected temperature must change with the readings in the same direction as
+        // the temperature of the model, otherwise the conversion is no longer monotonic.
+        for pair in table.points().windows(2) {
+            let reference_step = pair[1].reference.get() - pair[0].reference.get();
+            let model_step = raw_into_milli_celsius(pair[1].raw).get()
+                - raw_into_milli_celsius(pair[0].raw).get();
+            if reference_step == 0 || (reference_step > 0) != (model_step > 0) {
+                return Err(CalibrationError::NotMonotonic);
+            }
+        }
+
+        *self = table;
+        Ok(())
+    }
+
+    /// Correct `temperature`, converted from `raw` via the NTC model. Between two points, the
+    /// correction is interpolated linearly, beyond the outermost points it is kept constant.
+    pub fn apply(&self, raw: f32, temperature: MilliCelsius) -> MilliCelsius {
+        let points = self.points();
+        let corrections = &self.corrections[..points.len()];
+        let correction = match points.iter().position(|point| point.raw > raw) {
+            None => corrections.last().copied().unwrap_or(0),
+            Some(0) => corrections[0],
+            Some(index) => {
+                let (left, right) = (&points[index - 1], &points[index]);
+                let fraction = (raw - left.raw) / (right.raw - left.raw);
+                let step = (corrections[index] - corrections[index - 1]) as f32;
+                corrections[index - 1] + (fraction * step) as i32
+            }
+        };
+        MilliCelsius::new(temperature.get().saturating_add(correction))
+    }
+
+    /// Serialize the table in order to persist it.
+    pub fn to_bytes(&self) -> [u8; CALIBRATION_RECORD_SIZE] {
+        let mut bytes = [0u8; CALIBRATION_RECORD_SIZE];
+        bytes[0..4].copy_from_slice(&CALIBRATION_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&(self.len as u32).to_le_bytes());
+        for (i, point) in self.points().iter().enumerate() {
+            let offset = 8 + 8 * i;
+            bytes[offset..offset + 4].copy_from_slice(&point.raw.to_bits().to_le_bytes());
+            bytes[offset + 4..offset + 8].copy_from_slice(&point.reference.get().to_le_bytes());
+        }
+        let checksum = checksum(&bytes[..CALIBRATION_RECORD_SIZE - 4]);
+        bytes[CALIBRATION_RECORD_SIZE - 4..].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Deserialize a table serialized via `to_bytes`, or `None` if `bytes` do not hold a valid
+    /// table, e.g., because the flash is erased.
+    pub fn from_bytes(bytes: &[u8; CALIBRATION_RECORD_SIZE]) -> Option<Self> {
+        let word = |offset: usize| {
+            u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        let len = word(4) as usize;
+        if word(0) != CALIBRATION_MAGIC
+            || word(CALIBRATION_RECORD_SIZE - 4) != checksum(&bytes[..CALIBRATION_RECORD_SIZE - 4])
+            || len > MAX_CALIBRATION_POINTS
+        {
+            return None;
+        }
+
+        let mut table = CalibrationTable::EMPTY;
+        for i in 0..len {
+            let offset = 8 + 8 * i;
+            let point = CalibrationPoint {
+                raw: f32::from_bits(word(offset)),
+                reference: MilliCelsius::new(word(offset + 4) as i32),
+            };
+            table.insert(point).ok()?;
+        }
+        Some(table)
+    }
+}
+
+/// FNV-1a hash of `bytes`, used to detect corrupted tables.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811C_9DC5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Persists the calibration table in the last page of the flash.
+///
+/// Erasing and writing the flash stalls the CPU for several milliseconds, so the table should
+/// only be stored while the machine is idle.
+#[cfg(feature = "stm32")]
+pub struct CalibrationStorage {
+    flash: Flash<'static, Blocking>,
+}
+
+#[cfg(feature = "stm32")]
+impl CalibrationStorage {
+    /// Create a new `CalibrationStorage` instance that owns the `flash`.
+    pub fn new(flash: CalibrationFlash) -> Self {
+        CalibrationStorage {
+            flash: Flash::new_blocking(flash),
+        }
+    }
+
+    /// Load the persisted table, or `None` if no valid table is stored.
+    pub fn load(&mut self) -> Option<CalibrationTable> {
+        let mut bytes = [0u8; CALIBRATION_RECORD_SIZE];
+        self.flash
+            .blocking_read(CALIBRATION_PAGE_OFFSET, &mut bytes)
+            .ok()?;
+        CalibrationTable::from_bytes(&bytes)
+    }
+
+    /// Persist `table`, replacing the table stored before.
+    pub fn store(&mut self, table: &CalibrationTable) -> Result<(), flash::Error> {
+        self.clear()?;
+        self.flash
+            .blocking_write(CALIBRATION_PAGE_OFFSET, &table.to_bytes())
+    }
+
+    /// Remove the persisted table, such that the NTC model is used without correction.
+    pub fn clear(&mut self) -> Result<(), flash::Error> {
+        self.flash.blocking_erase(
+            CALIBRATION_PAGE_OFFSET,
+            CALIBRATION_PAGE_OFFSET + CALIBRATION_PAGE_SIZE,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::config::PROFILE;
+
+    fn point(celsius: f32, reference: i32) -> CalibrationPoint {
+        CalibrationPoint {
+            raw: PROFILE.ntc.raw(celsius).round(),
+            reference: MilliCelsius::new(reference),
+        }
+    }
+
+    fn table(points: &[CalibrationPoint]) -> CalibrationTable {
+        let mut table = CalibrationTable::EMPTY;
+        for point in points {
+            table.insert(*point).unwrap();
+        }
+        table
+    }
+
+    fn corrected(table: &CalibrationTable, raw: f32) -> i32 {
+        table.apply(raw, raw_into_milli_celsius(raw)).get()
+    }
+
+    #[test]
+    fn empty_table_keeps_model() {
+        let raw = PROFILE.ntc.raw(93.0);
+        assert_eq!(
+            corrected(&CalibrationTable::EMPTY, raw),
+            raw_into_milli_celsius(raw).get()
+        );
+    }
+
+    #[test]
+    fn points_are_matched_and_interpolated() {
+        let low = point(40.0, 42_000);
+        let high = point(90.0, 88_000);
+        let table = table(&[high, low]);
+        assert_eq!(table.points(), &[low, high]);
+
+        assert_eq!(corrected(&table, low.raw), 42_000);
+        assert_eq!(corrected(&table, high.raw), 88_000);
+        // Halfway between the points, the correction is the mean of both corrections.
+        let correction = |point: CalibrationPoint| {
+            point.reference.get() - raw_into_milli_celsius(point.raw).get()
+        };
+        let middle = (low.raw + high.raw) / 2.0;
+        let expected =
+            raw_into_milli_celsius(middle).get() + (correction(low) + correction(high)) / 2;
+        assert!((corrected(&table, middle) - expected).abs() <= 1);
+        // Beyond the points, the outermost correction is kept.
+        let cold = PROFILE.ntc.raw(20.0);
+        assert_eq!(
+            corrected(&table, cold),
+            raw_into_milli_celsius(cold).get() + correction(low)
+        );
+    }
+
+    #[test]
+    fn contradicting_points_are_rejected() {
+        let mut table = table(&[point(40.0, 40_000), point(90.0, 90_000)]);
+        let before = table;
+        assert_eq!(
+            table.insert(point(60.0, 95_000)),
+            Err(CalibrationError::NotMonotonic)
+        );
+        assert_eq!(table, before);
+
+        for celsius in 0..MAX_CALIBRATION_POINTS - 2 {
+            let celsius = 50 + 5 * celsius as i32;
+            table.insert(point(celsius as f32, celsius * 1000)).unwrap();
+        }
+        assert_eq!(
+            table.insert(point(30.0, 30_000)),
+            Err(CalibrationError::TableFull)
+        );
+    }
+
+    #[test]
+    fn tables_are_serialized() {
+        let table = table(&[
+            point(25.0, 24_500),
+            point(60.0, 61_000),
+            point(93.0, 92_000),
+        ]);
+        assert_eq!(CalibrationTable::from_bytes(&table.to_bytes()), Some(table));
+
+        let mut corrupted = table.to_bytes();
+        corrupted[10] ^= 1;
+        assert_eq!(CalibrationTable::from_bytes(&corrupted), None);
+        // Erased flash.
+        assert_eq!(
+            CalibrationTable::from_bytes(&[0xFF; CALIBRATION_RECORD_SIZE]),
+            None
+        );
+    }
+}
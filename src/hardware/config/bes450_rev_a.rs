@@ -0,0 +1,129 @@
+//!
+//! Pin map and profile of the original controller board (revision A) of the
+//! Sage/Breville Bambino (BES450).
+//!
+
+use super::MachineProfile;
+use crate::hardware::ntc::{NtcModel, NtcParameters, NtcPosition};
+#[cfg(feature = "stm32")]
+use embassy_stm32::{peripherals, Peripherals};
+
+/// The profile of the Bambino.
+pub const PROFILE: MachineProfile = MachineProfile {
+    heater_power_w: 1560,
+    mg_per_pulse: 440,
+    ntc: NtcParameters {
+        /*
+        The datasheet of the NTC is not available, so the Beta model is fitted to the
+        following readings (assuming a 10 kΩ divider resistor):
+        ADC Value -> Temperature
+        1000 -> 17
+        1339 -> 25
+        2064 -> 44
+        2997 -> 71
+        3341 -> 81
+        Above 81 °C, i.e., in the brew and steam range, the model is extrapolated and not
+        verified against readings.
+        */
+        model: NtcModel::Beta {
+            reference_ohm: 22_800.0,
+            reference_celsius: 25.0,
+            beta: 4223.0,
+        },
+        position: NtcPosition::HighSide,
+        divider_ohm: 10_000.0,
+        // The divider is fed by the 3.3 V regulator that also supplies VDDA, which is the
+        // reference of the ADC (VREF+ is bonded to VDDA on the STM32F070CB). Thus, drift of
+        // the rail cancels out and compensating it via VREFINT would only add error.
+        divider_supply_mv: 3300,
+        ratiometric: true,
+        adc_reference_mv: 3300,
+        adc_full_scale: 4095,
+    },
+};
+
+/// The one cup button.
+#[cfg(feature = "stm32")]
+pub type OneCupButtonPin = peripherals::PA0;
+/// The EXTI channel of the one cup button.
+#[cfg(feature = "stm32")]
+pub type OneCupButtonExti = peripherals::EXTI0;
+/// The two cups button.
+#[cfg(feature = "stm32")]
+pub type TwoCupButtonPin = peripherals::PA1;
+/// The EXTI channel of the two cups button.
+#[cfg(feature = "stm32")]
+pub type TwoCupButtonExti = peripherals::EXTI1;
+/// The steam button.
+#[cfg(feature = "stm32")]
+pub type SteamButtonPin = peripherals::PA2;
+/// The EXTI channel of the steam button.
+#[cfg(feature = "stm32")]
+pub type SteamButtonExti = peripherals::EXTI2;
+/// The hot water button.
+#[cfg(feature = "stm32")]
+pub type HotWaterButtonPin = peripherals::PA3;
+/// The EXTI channel of the hot water button.
+#[cfg(feature = "stm32")]
+pub type HotWaterButtonExti = peripherals::EXTI3;
+/// The pulse signal of the flow meter.
+#[cfg(feature = "stm32")]
+pub type FlowSignalPin = peripherals::PA7;
+/// The EXTI channel of the flow meter pulse signal.
+#[cfg(feature = "stm32")]
+pub type FlowSignalExti = peripherals::EXTI7;
+/// The power supply of the flow meter.
+#[cfg(feature = "stm32")]
+pub type FlowEnablePin = peripherals::PB11;
+/// The pin switching the heater.
+#[cfg(feature = "stm32")]
+pub type HeaterPin = peripherals::PB6;
+/// The LED of the one cup button.
+#[cfg(feature = "stm32")]
+pub type OneCupLedPin = peripherals::PA15;
+/// The LED of the two cups button.
+#[cfg(feature = "stm32")]
+pub type TwoCupLedPin = peripherals::PB3;
+/// The PWM output driving the pump.
+#[cfg(feature = "stm32")]
+pub type PumpPin = peripherals::PB8;
+/// The timer generating the PWM signal of the pump.
+#[cfg(feature = "stm32")]
+pub type PumpTimer = peripherals::TIM16;
+/// The pin switching the solenoid.
+#[cfg(feature = "stm32")]
+pub type SolenoidPin = peripherals::PA11;
+/// The ADC used to sample the NTC.
+#[cfg(feature = "stm32")]
+pub type NtcAdc = peripherals::ADC;
+/// The NTC.
+#[cfg(feature = "stm32")]
+pub type NtcPin = peripherals::PB1;
+/// The flash the calibration of the NTC is persisted in.
+#[cfg(feature = "stm32")]
+pub type CalibrationFlash = peripherals::FLASH;
+
+#[cfg(feature = "stm32")]
+impl super::BoardPeripherals {
+    /// Take the peripherals used by the drivers from `p`.
+    pub fn take(p: Peripherals) -> Self {
+        super::BoardPeripherals {
+            one_cup_button: (p.PA0, p.EXTI0),
+            two_cup_button: (p.PA1, p.EXTI1),
+            steam_button: (p.PA2, p.EXTI2),
+            hot_water_button: (p.PA3, p.EXTI3),
+            flow_signal: p.PA7,
+            flow_signal_exti: p.EXTI7,
+            flow_enable: p.PB11,
+            heater: p.PB6,
+            one_cup_led: p.PA15,
+            two_cup_led: p.PB3,
+            pump: p.PB8,
+            pump_timer: p.TIM16,
+            solenoid: p.PA11,
+            ntc_adc: p.ADC,
+            ntc: p.PB1,
+            calibration_flash: p.FLASH,
+        }
+    }
+}
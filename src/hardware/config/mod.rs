@@ -0,0 +1,73 @@
+//!
+//! The board specific configuration, i.e., the pin map and the machine profile.
+//!
+//! The board is selected via cargo feature. Exactly one of the following features must be enabled:
+//! - `bes450-rev-a`: The original controller board of the Sage/Breville Bambino (BES450).
+//! - `custom-board`: A custom replacement board, see `custom.rs`.
+//!
+
+#[cfg(all(feature = "bes450-rev-a", feature = "custom-board"))]
+compile_error!("The features `bes450-rev-a` and `custom-board` are mutually exclusive.");
+#[cfg(not(any(feature = "bes450-rev-a", feature = "custom-board")))]
+compile_error!("Select the board via the `bes450-rev-a` or `custom-board` feature.");
+
+#[cfg(feature = "bes450-rev-a")]
+mod bes450_rev_a;
+#[cfg(feature = "bes450-rev-a")]
+pub use bes450_rev_a::*;
+
+#[cfg(feature = "custom-board")]
+mod custom;
+#[cfg(feature = "custom-board")]
+pub use custom::*;
+
+use super::ntc::NtcParameters;
+
+/// Constants describing the machine the board is built into.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MachineProfile {
+    /// Electrical power of the heater in W when it is turned on.
+    pub heater_power_w: u32,
+    /// Amount of water per flow meter pulse in mg at 9 pulses per second
+    /// (pump at ~50% of it's power).
+    pub mg_per_pulse: u32,
+    /// The NTC measuring the water temperature and the circuit it is read with.
+    pub ntc: NtcParameters,
+}
+
+/// The peripherals used by the drivers, taken from `Peripherals` according to the pin map.
+#[cfg(feature = "stm32")]
+pub struct BoardPeripherals {
+    /// The one cup button and its EXTI channel.
+    pub one_cup_button: (OneCupButtonPin, OneCupButtonExti),
+    /// The two cups button and its EXTI channel.
+    pub two_cup_button: (TwoCupButtonPin, TwoCupButtonExti),
+    /// The steam button and its EXTI channel.
+    pub steam_button: (SteamButtonPin, SteamButtonExti),
+    /// The hot water button and its EXTI channel.
+    pub hot_water_button: (HotWaterButtonPin, HotWaterButtonExti),
+    /// The pulse signal of the flow meter.
+    pub flow_signal: FlowSignalPin,
+    /// The EXTI channel of the flow meter pulse signal.
+    pub flow_signal_exti: FlowSignalExti,
+    /// The power supply of the flow meter.
+    pub flow_enable: FlowEnablePin,
+    /// The pin switching the heater.
+    pub heater: HeaterPin,
+    /// The LED of the one cup button.
+    pub one_cup_led: OneCupLedPin,
+    /// The LED of the two cups button.
+    pub two_cup_led: TwoCupLedPin,
+    /// The PWM output driving the pump.
+    pub pump: PumpPin,
+    /// The timer generating the PWM signal of the pump.
+    pub pump_timer: PumpTimer,
+    /// The pin switching the solenoid.
+    pub solenoid: SolenoidPin,
+    /// The ADC used to sample the NTC.
+    pub ntc_adc: NtcAdc,
+    /// The NTC.
+    pub ntc: NtcPin,
+    /// The flash the calibration of the NTC is persisted in.
+    pub calibration_flash: CalibrationFlash,
+}
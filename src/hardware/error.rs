@@ -0,0 +1,28 @@
+//!
+//! Errors that may occur while creating the drivers.
+//!
+
+use embassy_executor::SpawnError;
+use embassy_sync::pubsub;
+
+/// An error that occurred while creating a driver.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DriverError {
+    /// The task of the driver could not be spawned.
+    Spawn(SpawnError),
+    /// The driver could not subscribe to the machine event bus.
+    EventBus(pubsub::Error),
+}
+
+impl From<SpawnError> for DriverError {
+    fn from(value: SpawnError) -> Self {
+        DriverError::Spawn(value)
+    }
+}
+
+impl From<pubsub::Error> for DriverError {
+    fn from(value: pubsub::Error) -> Self {
+        DriverError::EventBus(value)
+    }
+}
@@ -0,0 +1,264 @@
+//!
+//! The machine event bus that distributes the button, temperature, flow and fault events
+//! of the drivers to any number of independent subscribers, e.g., the UI logic, a logger
+//! and a safety supervisor.
+//!
+//! Events are published without waiting for the subscribers. If a subscriber does not keep
+//! up, it misses the oldest events and is notified via [`WaitResult::Lagged`].
+//!
+
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    pubsub::{self, PubSubChannel, Subscriber},
+};
+
+use super::{
+    buttons::ButtonStateTransitionEvent,
+    heater_safety::HeaterTrip,
+    temperature::{SensorFault, TemperatureReading},
+};
+use crate::units::Milligrams;
+
+pub use embassy_sync::pubsub::WaitResult;
+
+/// Number of events that are buffered per subscriber.
+pub const EVENT_QUEUE_CAPACITY: usize = 16;
+/// Maximum number of subscribers that can be alive at the same time.
+pub const MAX_SUBSCRIBERS: usize = 4;
+/// Maximum number of faults that are tracked as active at the same time.
+pub const MAX_ACTIVE_FAULTS: usize = 8;
+
+type MachineEventBus =
+    PubSubChannel<CriticalSectionRawMutex, MachineEvent, EVENT_QUEUE_CAPACITY, MAX_SUBSCRIBERS, 0>;
+
+/// A subscription to the machine events.
+pub type MachineEventSubscriber = Subscriber<
+    'static,
+    CriticalSectionRawMutex,
+    MachineEvent,
+    EVENT_QUEUE_CAPACITY,
+    MAX_SUBSCRIBERS,
+    0,
+>;
+
+static MACHINE_EVENTS: MachineEventBus = MachineEventBus::new();
+static ACTIVE_FAULTS: Mutex<CriticalSectionRawMutex, Cell<ActiveFaults>> =
+    Mutex::new(Cell::new(ActiveFaults::NONE));
+
+/// The bus and the active faults are global, thus the tests using them must not run in
+/// parallel.
+#[cfg(test)]
+pub(crate) static TEST_BUS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// A fault detected by one of the hardware components.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Fault {
+    /// The NTC measuring the water temperature is faulty.
+    Sensor(SensorFault),
+    /// The control board exceeds its temperature limit.
+    BoardOverTemperature,
+    /// The heater tripped and stays off until the trip is reset.
+    HeaterTrip(HeaterTrip),
+}
+
+/// The faults that were published and not cleared since, ordered from the oldest to the
+/// most recent one.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ActiveFaults {
+    faults: [Option<Fault>; MAX_ACTIVE_FAULTS],
+}
+
+impl ActiveFaults {
+    /// No active fault.
+    pub const NONE: ActiveFaults = ActiveFaults {
+        faults: [None; MAX_ACTIVE_FAULTS],
+    };
+
+    /// Whether no fault is active.
+    pub fn is_empty(&self) -> bool {
+        self.faults[0].is_none()
+    }
+
+    /// Whether `fault` is active.
+    pub fn contains(&self, fault: Fault) -> bool {
+        self.iter().any(|active| active == fault)
+    }
+
+    /// The active faults from the oldest to the most recent one.
+    pub fn iter(&self) -> impl Iterator<Item = Fault> + '_ {
+        self.faults.iter().map_while(|fault| *fault)
+    }
+
+    /// The most recent active fault, if any.
+    pub fn latest(&self) -> Option<Fault> {
+        self.iter().last()
+    }
+
+    /// Mark `fault` as active. If `MAX_ACTIVE_FAULTS` faults are active already, the oldest
+    /// one is discarded.
+    fn insert(&mut self, fault: Fault) {
+        if self.contains(fault) {
+            return;
+        }
+        let len = self.iter().count();
+        if len == MAX_ACTIVE_FAULTS {
+            self.faults.rotate_left(1);
+            self.faults[MAX_ACTIVE_FAULTS - 1] = Some(fault);
+        } else {
+            self.faults[len] = Some(fault);
+        }
+    }
+
+    /// Mark `fault` as no longer active.
+    fn remove(&mut self, fault: Fault) {
+        let index = self.iter().position(|active| active == fault);
+        if let Some(index) = index {
+            self.faults[index..].rotate_left(1);
+            self.faults[MAX_ACTIVE_FAULTS - 1] = None;
+        }
+    }
+}
+
+/// An event of the machine.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MachineEvent {
+    /// A button was pressed or released.
+    Button(ButtonStateTransitionEvent),
+    /// A new water temperature was measured.
+    Temperature(TemperatureReading),
+    /// The flow meter measured more water. Contains the total amount flowed so far.
+    Flow(Milligrams),
+    /// A fault was detected.
+    Fault(Fault),
+}
+
+/// Subscribe to the machine events. Only events published after subscribing are received.
+///
+/// Fails with [`pubsub::Error::MaximumSubscribersReached`] if there are already
+/// `MAX_SUBSCRIBERS` subscribers.
+pub fn subscribe() -> Result<MachineEventSubscriber, pubsub::Error> {
+    MACHINE_EVENTS.subscriber()
+}
+
+/// Publish `event` to all current subscribers without waiting for them.
+///
+/// Publishing a fault marks it as active.
+pub fn publish(event: MachineEvent) {
+    if let MachineEvent::Fault(fault) = event {
+        ACTIVE_FAULTS.lock(|active_faults| {
+            let mut faults = active_faults.get();
+            faults.insert(fault);
+            active_faults.set(faults);
+        });
+    }
+    MACHINE_EVENTS
+        .immediate_publisher()
+        .publish_immediate(event);
+}
+
+/// Clear `fault` after its cause is gone. Other active faults are not affected.
+pub fn clear_fault(fault: Fault) {
+    ACTIVE_FAULTS.lock(|active_faults| {
+        let mut faults = active_faults.get();
+        faults.remove(fault);
+        active_faults.set(faults);
+    });
+}
+
+/// The faults that were published and not cleared since.
+pub fn active_faults() -> ActiveFaults {
+    ACTIVE_FAULTS.lock(|active_faults| active_faults.get())
+}
+
+/// The most recent fault that was published and not cleared since, if any.
+pub fn active_fault() -> Option<Fault> {
+    active_faults().latest()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hardware::temperature::HeaterPhase,
+        units::{CelsiusPerSecond, MilliCelsius},
+    };
+    use embassy_time::Instant;
+
+    #[test]
+    fn all_subscribers_receive_events() {
+        let _bus = TEST_BUS.lock().unwrap();
+        let mut ui = subscribe().unwrap();
+        let mut logger = subscribe().unwrap();
+
+        publish(MachineEvent::Flow(Milligrams::new(440)));
+        let reading = TemperatureReading {
+            timestamp: Instant::from_millis(100),
+            temperature: MilliCelsius::new(93_000),
+            rate: CelsiusPerSecond::ZERO,
+            heater_phase: HeaterPhase::Off,
+        };
+        publish(MachineEvent::Temperature(reading));
+
+        for subscriber in [&mut ui, &mut logger] {
+            assert_eq!(
+                subscriber.try_next_message_pure(),
+                Some(MachineEvent::Flow(Milligrams::new(440)))
+            );
+            assert_eq!(
+                embassy_futures::block_on(subscriber.next_message_pure()),
+                MachineEvent::Temperature(reading)
+            );
+            assert_eq!(subscriber.try_next_message_pure(), None);
+        }
+    }
+
+    #[test]
+    fn faults_stay_active_until_cleared() {
+        let _bus = TEST_BUS.lock().unwrap();
+        let open_circuit = Fault::Sensor(SensorFault::OpenCircuit);
+        let short_circuit = Fault::Sensor(SensorFault::ShortCircuit);
+        publish(MachineEvent::Fault(open_circuit));
+        assert_eq!(active_fault(), Some(open_circuit));
+
+        clear_fault(short_circuit);
+        assert_eq!(active_fault(), Some(open_circuit));
+        clear_fault(open_circuit);
+        assert_eq!(active_fault(), None);
+    }
+
+    #[test]
+    fn concurrent_faults_are_cleared_individually() {
+        let _bus = TEST_BUS.lock().unwrap();
+        let trip = Fault::HeaterTrip(HeaterTrip::OverTemperature);
+        publish(MachineEvent::Fault(trip));
+        publish(MachineEvent::Fault(Fault::BoardOverTemperature));
+        publish(MachineEvent::Fault(trip));
+        let faults: Vec<_> = active_faults().iter().collect();
+        assert_eq!(faults, [trip, Fault::BoardOverTemperature]);
+
+        clear_fault(trip);
+        assert_eq!(active_fault(), Some(Fault::BoardOverTemperature));
+        clear_fault(Fault::BoardOverTemperature);
+        assert!(active_faults().is_empty());
+    }
+
+    #[test]
+    fn slow_subscribers_lag() {
+        let _bus = TEST_BUS.lock().unwrap();
+        let mut subscriber = subscribe().unwrap();
+        for amount in 0..(EVENT_QUEUE_CAPACITY as u32 + 2) {
+            publish(MachineEvent::Flow(Milligrams::new(amount)));
+        }
+
+        assert_eq!(subscriber.try_next_message(), Some(WaitResult::Lagged(2)));
+        assert_eq!(
+            subscriber.try_next_message_pure(),
+            Some(MachineEvent::Flow(Milligrams::new(2)))
+        );
+    }
+}
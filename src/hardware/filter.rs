@@ -0,0 +1,415 @@
+//!
+//! The filter pipeline the NTC readings are processed with before they are converted into
+//! a temperature.
+//!
+//! Each reading passes through the following stages:
+//! 1. Oversampling: `4^n` ADC samples are summed up and decimated, which adds `n` bits of
+//!    resolution given there is enough noise on the signal.
+//! 2. Median: The median of the last readings rejects single spikes.
+//! 3. Exponential moving average: Smooths the remaining noise at the cost of latency.
+//!
+//! Additionally, the rate of change of the temperature is estimated by a [`RateEstimator`]
+//! via linear regression over the readings of a configurable time window.
+//!
+
+use embassy_time::{Duration, Instant};
+
+use crate::units::{CelsiusPerSecond, MilliCelsius};
+
+/// The maximal number of extra bits of resolution gained by oversampling.
+pub const MAX_OVERSAMPLING_BITS: u8 = 4;
+/// The maximal window of the median filter.
+pub const MAX_MEDIAN_WINDOW: usize = 9;
+/// The maximal number of readings the rate of change is estimated from.
+pub const MAX_RATE_SAMPLES: usize = 32;
+
+/// The parameters of a [`FilterPipeline`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FilterConfig {
+    oversampling_bits: u8,
+    median_window: u8,
+    ema_alpha: f32,
+}
+
+impl FilterConfig {
+    /// Low noise for keeping the brew temperature stable.
+    pub const BREW: FilterConfig = FilterConfig {
+        oversampling_bits: 2,
+        median_window: 5,
+        ema_alpha: 0.3,
+    };
+
+    /// Low latency for the fast heat up to the steam temperature.
+    pub const STEAM: FilterConfig = FilterConfig {
+        oversampling_bits: 2,
+        median_window: 3,
+        ema_alpha: 1.0,
+    };
+
+    /// Create a new configuration that sums up `4^oversampling_bits` samples per reading,
+    /// takes the median over the last `median_window` readings and smooths the result with
+    /// an exponential moving average using the weight `ema_alpha` for the new reading.
+    ///
+    /// A `median_window` of 1 and an `ema_alpha` of 1.0 disable the respective stage.
+    /// Returns `None` if `oversampling_bits` exceeds `MAX_OVERSAMPLING_BITS`, if
+    /// `median_window` is not odd or exceeds `MAX_MEDIAN_WINDOW`, or if `ema_alpha` is not
+    /// within (0.0, 1.0].
+    pub fn new(oversampling_bits: u8, median_window: u8, ema_alpha: f32) -> Option<Self> {
+        let valid = oversampling_bits <= MAX_OVERSAMPLING_BITS
+            && median_window % 2 == 1
+            && median_window as usize <= MAX_MEDIAN_WINDOW
+            && ema_alpha > 0.0
+            && ema_alpha <= 1.0;
+        valid.then_some(FilterConfig {
+            oversampling_bits,
+            median_window,
+            ema_alpha,
+        })
+    }
+
+    /// The extra bits of resolution gained by oversampling.
+    pub fn oversampling_bits(&self) -> u8 {
+        self.oversampling_bits
+    }
+
+    /// The number of ADC samples per reading.
+    pub fn samples_per_reading(&self) -> u16 {
+        1 << (2 * self.oversampling_bits)
+    }
+
+    /// The number of readings the median is taken over.
+    pub fn median_window(&self) -> u8 {
+        self.median_window
+    }
+
+    /// The weight of a new reading in the exponential moving average.
+    pub fn ema_alpha(&self) -> f32 {
+        self.ema_alpha
+    }
+}
+
+/// Filters the readings of the NTC according to a [`FilterConfig`].
+pub struct FilterPipeline {
+    config: FilterConfig,
+    window: [f32; MAX_MEDIAN_WINDOW],
+    window_len: usize,
+    window_next: usize,
+    average: Option<f32>,
+}
+
+impl FilterPipeline {
+    /// Create a new pipeline filtering according to `config`.
+    pub fn new(config: FilterConfig) -> Self {
+        FilterPipeline {
+            config,
+            window: [0.0; MAX_MEDIAN_WINDOW],
+            window_len: 0,
+            window_next: 0,
+            average: None,
+        }
+    }
+
+    /// The current configuration.
+    pub fn config(&self) -> FilterConfig {
+        self.config
+    }
+
+    /// Switch to `config`. This resets the state of the pipeline.
+    pub fn configure(&mut self, config: FilterConfig) {
+        *self = FilterPipeline::new(config);
+    }
+
+    /// Feed the `sum` of `config().samples_per_reading()` ADC samples into the pipeline and
+    /// get the filtered reading in ADC counts.
+    pub fn update(&mut self, sum: u32) -> f32 {
+        let reading = self.decimate(sum);
+        let median = self.median(reading);
+        let average = match self.average {
+            Some(average) => average + self.config.ema_alpha * (median - average),
+            None => median,
+        };
+        self.average = Some(average);
+        average
+    }
+
+    fn decimate(&self, sum: u32) -> f32 {
+        // Dropping `n` of the `2n` bits added by summing up `4^n` samples keeps `n` bits
+        // of extra resolution, which are represented as fraction of an ADC count.
+        let bits = self.config.oversampling_bits;
+        let decimated = sum >> bits;
+        decimated as f32 / (1u32 << bits) as f32
+    }
+
+    fn median(&mut self, reading: f32) -> f32 {
+        let window = self.config.median_window as usize;
+        self.window[self.window_next] = reading;
+        self.window_next = (self.window_next + 1) % window;
+        self.window_len = (self.window_len + 1).min(window);
+
+        // A plain insertion sort is sufficient for the tiny window and keeps the firmware small.
+        let mut sorted = self.window;
+        for i in 1..self.window_len {
+            let mut j = i;
+            while j > 0 && sorted[j - 1] > sorted[j] {
+                sorted.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+        sorted[self.window_len / 2]
+    }
+}
+
+/// Estimates the rate of change of the temperature by fitting a line through the readings
+/// of the last `window`.
+///
+/// At most `MAX_RATE_SAMPLES` readings are kept, thus readings that follow the previous one
+/// within `window / MAX_RATE_SAMPLES` are skipped.
+pub struct RateEstimator {
+    window: Duration,
+    samples: [(Instant, MilliCelsius); MAX_RATE_SAMPLES],
+    len: usize,
+    next: usize,
+    newest: Option<Instant>,
+    rate: CelsiusPerSecond,
+}
+
+impl RateEstimator {
+    /// Create a new estimator fitting the readings of the last `window`.
+    pub fn new(window: Duration) -> Self {
+        RateEstimator {
+            window,
+            samples: [(Instant::MIN, MilliCelsius::default()); MAX_RATE_SAMPLES],
+            len: 0,
+            next: 0,
+            newest: None,
+            rate: CelsiusPerSecond::ZERO,
+        }
+    }
+
+    /// The window the readings are fitted over.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Fit the readings of the last `window` from now on. This discards all readings.
+    pub fn set_window(&mut self, window: Duration) {
+        *self = RateEstimator::new(window);
+    }
+
+    /// The rate estimated from the readings so far. This is zero until there are at least
+    /// two readings within the window.
+    pub fn rate(&self) -> CelsiusPerSecond {
+        self.rate
+    }
+
+    /// Add the `temperature` measured at `timestamp` and get the new estimate.
+    pub fn update(&mut self, timestamp: Instant, temperature: MilliCelsius) -> CelsiusPerSecond {
+        let spacing = self.window / MAX_RATE_SAMPLES as u32;
+        if let Some(newest) = self.newest {
+            if timestamp.saturating_duration_since(newest) < spacing {
+                return self.rate;
+            }
+        }
+
+        self.samples[self.next] = (timestamp, temperature);
+        self.next = (self.next + 1) % MAX_RATE_SAMPLES;
+        self.len = (self.len + 1).min(MAX_RATE_SAMPLES);
+        self.newest = Some(timestamp);
+        self.rate = self.fit(timestamp);
+        self.rate
+    }
+
+    fn fit(&self, now: Instant) -> CelsiusPerSecond {
+        // The order of the samples does not matter for the regression.
+        let samples = || {
+            self.samples[..self.len]
+                .iter()
+                .filter(|(timestamp, _)| now.saturating_duration_since(*timestamp) <= self.window)
+                .map(|(timestamp, temperature)| {
+                    let age = now.saturating_duration_since(*timestamp);
+                    (
+                        -(age.as_micros() as f32) / 1e6,
+                        temperature.as_celsius_f32(),
+                    )
+                })
+        };
+
+        let (mut count, mut sum_t, mut sum_y) = (0, 0f32, 0f32);
+        for (t, y) in samples() {
+            count += 1;
+            sum_t += t;
+            sum_y += y;
+        }
+        if count < 2 {
+            return CelsiusPerSecond::ZERO;
+        }
+
+        let (mean_t, mean_y) = (sum_t / count as f32, sum_y / count as f32);
+        let (mut covariance, mut variance) = (0f32, 0f32);
+        for (t, y) in samples() {
+            covariance += (t - mean_t) * (y - mean_y);
+            variance += (t - mean_t) * (t - mean_t);
+        }
+        if variance <= 0.0 {
+            return CelsiusPerSecond::ZERO;
+        }
+        CelsiusPerSecond::new(covariance / variance).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unfiltered(oversampling_bits: u8) -> FilterConfig {
+        FilterConfig::new(oversampling_bits, 1, 1.0).unwrap()
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        assert_eq!(FilterConfig::new(MAX_OVERSAMPLING_BITS + 1, 1, 1.0), None);
+        assert_eq!(FilterConfig::new(0, 4, 1.0), None);
+        assert_eq!(FilterConfig::new(0, MAX_MEDIAN_WINDOW as u8 + 2, 1.0), None);
+        assert_eq!(FilterConfig::new(0, 1, 0.0), None);
+        assert_eq!(FilterConfig::new(0, 1, 1.5), None);
+        for config in [FilterConfig::BREW, FilterConfig::STEAM] {
+            let FilterConfig {
+                oversampling_bits,
+                median_window,
+                ema_alpha,
+            } = config;
+            assert_eq!(
+                FilterConfig::new(oversampling_bits, median_window, ema_alpha),
+                Some(config)
+            );
+        }
+    }
+
+    #[test]
+    fn oversampling_adds_resolution() {
+        let mut pipeline = FilterPipeline::new(unfiltered(2));
+        // Half of the 16 samples read 100, the other half 101.
+        assert_eq!(pipeline.update(8 * 100 + 8 * 101), 100.5);
+
+        let mut pipeline = FilterPipeline::new(unfiltered(0));
+        assert_eq!(pipeline.update(100), 100.0);
+    }
+
+    #[test]
+    fn median_rejects_spikes() {
+        let mut pipeline = FilterPipeline::new(FilterConfig::new(0, 3, 1.0).unwrap());
+        for sum in [2000, 2001, 4095, 2002, 0, 2003] {
+            let reading = pipeline.update(sum);
+            assert!((2000.0..=2003.0).contains(&reading), "reading={reading}");
+        }
+    }
+
+    #[test]
+    fn average_converges() {
+        let mut pipeline = FilterPipeline::new(FilterConfig::new(0, 1, 0.5).unwrap());
+        assert_eq!(pipeline.update(1000), 1000.0);
+        assert_eq!(pipeline.update(2000), 1500.0);
+        for _ in 0..32 {
+            pipeline.update(2000);
+        }
+        assert!((pipeline.update(2000) - 2000.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn configure_resets_the_state() {
+        let mut pipeline = FilterPipeline::new(FilterConfig::BREW);
+        let samples = FilterConfig::BREW.samples_per_reading() as u32;
+        pipeline.update(1000 * samples);
+        pipeline.configure(FilterConfig::STEAM);
+        assert_eq!(pipeline.config(), FilterConfig::STEAM);
+        let samples = FilterConfig::STEAM.samples_per_reading() as u32;
+        assert_eq!(pipeline.update(3000 * samples), 3000.0);
+    }
+
+    fn at_ms(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn rate_of_linear_ramp() {
+        let mut estimator = RateEstimator::new(Duration::from_secs(1));
+        assert_eq!(
+            estimator.update(at_ms(0), MilliCelsius::new(20_000)),
+            CelsiusPerSecond::ZERO
+        );
+        // 2.5 °C/s, sampled every 10 ms.
+        for ms in (10..2000).step_by(10) {
+            estimator.update(at_ms(ms), MilliCelsius::new(20_000 + 25 * ms as i32 / 10));
+        }
+        assert!(
+            (estimator.rate().get() - 2.5).abs() < 0.01,
+            "rate={:?}",
+            estimator.rate()
+        );
+    }
+
+    #[test]
+    fn old_readings_leave_the_window() {
+        let mut estimator = RateEstimator::new(Duration::from_millis(500));
+        for ms in (0..1000).step_by(20) {
+            estimator.update(at_ms(ms), MilliCelsius::new(90_000 - 10 * ms as i32));
+        }
+        // The temperature stays constant after a gap.
+        for ms in (3000..3600).step_by(20) {
+            estimator.update(at_ms(ms), MilliCelsius::new(60_000));
+        }
+        assert_eq!(estimator.rate(), CelsiusPerSecond::ZERO);
+    }
+
+    #[test]
+    fn set_window_discards_readings() {
+        let mut estimator = RateEstimator::new(Duration::from_secs(1));
+        estimator.update(at_ms(0), MilliCelsius::new(20_000));
+        estimator.update(at_ms(500), MilliCelsius::new(30_000));
+        assert!(estimator.rate() > CelsiusPerSecond::ZERO);
+
+        estimator.set_window(Duration::from_secs(2));
+        assert_eq!(estimator.window(), Duration::from_secs(2));
+        assert_eq!(estimator.rate(), CelsiusPerSecond::ZERO);
+        assert_eq!(
+            estimator.update(at_ms(600), MilliCelsius::new(20_000)),
+            CelsiusPerSecond::ZERO
+        );
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn rate_of_constant_temperature_is_zero(
+            temperature in -20_000i32..200_000,
+            window_ms in 100u64..5000,
+            intervals in proptest::collection::vec(1u64..200, 1..128),
+        ) {
+            let mut estimator = RateEstimator::new(Duration::from_millis(window_ms));
+            let mut now = 0;
+            for interval in intervals {
+                now += interval;
+                let rate = estimator.update(at_ms(now), MilliCelsius::new(temperature));
+                proptest::prop_assert!(rate.get().abs() < 1e-3);
+            }
+        }
+
+        #[test]
+        fn output_stays_within_inputs(
+            oversampling_bits in 0..=MAX_OVERSAMPLING_BITS,
+            median_half_window in 0..=(MAX_MEDIAN_WINDOW as u8 / 2),
+            ema_alpha in 0.01f32..=1.0,
+            readings in proptest::collection::vec(0u32..4096, 1..64),
+        ) {
+            let median_window = 2 * median_half_window + 1;
+            let config = FilterConfig::new(oversampling_bits, median_window, ema_alpha).unwrap();
+            let mut pipeline = FilterPipeline::new(config);
+            let samples = config.samples_per_reading() as u32;
+            let (min, max) = (readings.iter().min().unwrap(), readings.iter().max().unwrap());
+            for reading in &readings {
+                let filtered = pipeline.update(reading * samples);
+                proptest::prop_assert!(*min as f32 - 0.01 <= filtered && filtered <= *max as f32 + 0.01);
+            }
+        }
+    }
+}
@@ -4,70 +4,107 @@
 //!
 #![allow(clippy::new_without_default)]
 
-use defmt::*;
-use embassy_executor::Spawner;
-use embassy_stm32::exti::{Channel as _, ExtiInput};
-use embassy_stm32::{
-    gpio::{self, AnyPin, Pin},
-    Peripherals,
+use embassy_time::Duration;
+
+use super::config::PROFILE;
+use crate::units::{Milligrams, MlPerSecond};
+#[cfg(feature = "stm32")]
+use {
+    super::{
+        config::{FlowEnablePin, FlowSignalExti, FlowSignalPin},
+        events::{self, MachineEvent},
+        traits::FlowSensor,
+    },
+    embassy_executor::{SpawnError, Spawner},
+    embassy_stm32::exti::{Channel as _, ExtiInput},
+    embassy_stm32::gpio::{self, AnyPin, Pin},
+    embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex,
+    embassy_sync::signal::Signal,
+    embassy_time::Instant,
+    portable_atomic::{AtomicU32, AtomicU64},
 };
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::signal::Signal;
-use embassy_time::{Duration, Instant};
-use portable_atomic::AtomicU32;
 
-static TOTAL_FLOW_IN_MG_SIGNAL: Signal<ThreadModeRawMutex, u32> = Signal::new();
+#[cfg(feature = "stm32")]
+static TOTAL_FLOW_IN_MG_SIGNAL: Signal<CriticalSectionRawMutex, Milligrams> = Signal::new();
+#[cfg(feature = "stm32")]
 static TOTAL_FLOW_IN_MG: AtomicU32 = AtomicU32::new(0);
+#[cfg(feature = "stm32")]
 static PULSE_CTR: AtomicU32 = AtomicU32::new(0);
+#[cfg(feature = "stm32")]
+static PULSES_PER_SECOND: AtomicU32 = AtomicU32::new(0);
+#[cfg(feature = "stm32")]
+static LAST_PULSE_TICKS: AtomicU64 = AtomicU64::new(0);
 
-// Value optimized for 9 pulses per second (pump at ~50% of it's power).
-const MG_PER_PULSE: u32 = 440;
+/// Amount of water per pulse in mg.
+/// Value optimized for 9 pulses per second (pump at ~50% of it's power).
+pub const MG_PER_PULSE: u32 = PROFILE.mg_per_pulse;
 
 /// The flow meter of the machine used to measure the water flow.
+#[cfg(feature = "stm32")]
 pub struct FlowMeter<'a> {
     flow_enable: gpio::Output<'a, AnyPin>,
 }
 
+#[cfg(feature = "stm32")]
+impl FlowMeter<'static> {
+    /// Create a new `FlowMeter` instance that owns the pulse `signal` pin, its EXTI channel
+    /// and the `enable` pin powering the flow meter, and spawn the task counting the pulses
+    /// on `spawner`.
+    pub fn new(
+        spawner: &Spawner,
+        signal: FlowSignalPin,
+        signal_exti: FlowSignalExti,
+        enable: FlowEnablePin,
+    ) -> Result<Self, SpawnError> {
+        let (flow_meter, runner) = FlowMeter::new_with_runner(signal, signal_exti, enable);
+        spawner.spawn(flowmeter_task(runner))?;
+
+        Ok(flow_meter)
+    }
+}
+
+#[cfg(feature = "stm32")]
 impl<'a> FlowMeter<'a> {
-    /// Create a new `FlowMeter` instance.
-    ///
-    /// # Safety
-    /// This is only safe to be called once.
-    ///
-    /// # Panics
-    /// If there is not enough memory to spawn a new task.
-    pub unsafe fn new(spawner: &mut Spawner) -> Self {
-        let p = unsafe { Peripherals::steal() };
-        let flow_enable_pin = p.PB11.degrade();
+    /// Create a new `FlowMeter` instance that owns the pulse `signal` pin, its EXTI channel
+    /// and the `enable` pin powering the flow meter, without spawning a task. The pulses
+    /// are only counted while the returned runner is running.
+    pub fn new_with_runner(
+        signal: FlowSignalPin,
+        signal_exti: FlowSignalExti,
+        enable: FlowEnablePin,
+    ) -> (Self, FlowMeterRunner<'a>) {
+        let flow_enable_pin = enable.degrade();
         let flow_enable = gpio::Output::new(flow_enable_pin, gpio::Level::Low, gpio::Speed::Low);
 
-        spawner.spawn(flowmeter_task()).unwrap();
+        let signal_input = gpio::Input::new(signal.degrade(), gpio::Pull::None);
+        let signal = ExtiInput::new(signal_input, signal_exti.degrade());
 
-
-        FlowMeter {flow_enable}
+        (FlowMeter { flow_enable }, FlowMeterRunner { signal })
     }
 
     /// The amount of water flowed so far.
-    pub fn flowed_mg(&self) -> u32 {
-        TOTAL_FLOW_IN_MG.load(portable_atomic::Ordering::SeqCst)
+    pub fn flowed(&self) -> Milligrams {
+        total_flow()
+    }
+
+    /// The current flow rate. This is zero if there was no pulse within the last second.
+    pub fn flow_rate(&self) -> MlPerSecond {
+        current_flow_rate()
     }
 
-    /// Wait until the flowed milligram value received an update and return the new value.
-    pub async fn wait_for_next_update(&self) -> u32 {
+    /// Wait until the flowed amount received an update and return the new value.
+    ///
+    /// Only a single waiter is supported, use the machine event bus in order to observe
+    /// the updates from multiple places.
+    pub async fn wait_for_next_update(&self) -> Milligrams {
         defmt::assert!(self.is_enabled());
         TOTAL_FLOW_IN_MG_SIGNAL.wait().await
     }
 
-    /// Wait until the specified amount of water has been poured.
-    pub async fn wait_for_amount(&self, amount_in_mg: u32) {
+    /// Wait until the specified `amount` of water has been poured.
+    pub async fn wait_for_amount(&self, amount: Milligrams) {
         defmt::assert!(self.is_enabled());
-        let start_mg = self.flowed_mg();
-        loop {
-            let new_value = self.wait_for_next_update().await;
-            if new_value.wrapping_sub(start_mg) >= amount_in_mg {
-                return;
-            }
-        }
+        FlowSensor::wait_for_amount(self, amount).await;
     }
 
     /// Number of pulses counted so far.
@@ -91,46 +128,95 @@ impl<'a> FlowMeter<'a> {
     }
 }
 
-struct FlowMeterTask<'a> {
-    signal: ExtiInput<'a, AnyPin>,
+/// The amount of water flowed so far.
+#[cfg(feature = "stm32")]
+pub(crate) fn total_flow() -> Milligrams {
+    Milligrams::new(TOTAL_FLOW_IN_MG.load(portable_atomic::Ordering::SeqCst))
 }
 
-impl<'a> FlowMeterTask<'a> {
-    /// Create a new `FlowMeter`` instance.
-    pub fn new() -> Self {
-        let p = unsafe { Peripherals::steal() };
+/// The current flow rate. This is zero if there was no pulse within the last second.
+#[cfg(feature = "stm32")]
+pub(crate) fn current_flow_rate() -> MlPerSecond {
+    let last_pulse = Instant::from_ticks(LAST_PULSE_TICKS.load(portable_atomic::Ordering::SeqCst));
+    if last_pulse.elapsed() >= Duration::from_secs(1) {
+        return MlPerSecond::ZERO;
+    }
+    PulseConverter::flow_rate(PULSES_PER_SECOND.load(portable_atomic::Ordering::SeqCst))
+}
 
-        let signal_input: AnyPin = p.PA7.degrade();
+#[cfg(feature = "stm32")]
+impl<'a> FlowSensor for FlowMeter<'a> {
+    fn flowed(&self) -> Milligrams {
+        FlowMeter::flowed(self)
+    }
 
-        let signal_input = gpio::Input::new(signal_input.degrade(), gpio::Pull::None);
-        let signal = ExtiInput::new(signal_input, p.EXTI4.degrade());
+    async fn wait_for_next_update(&self) -> Milligrams {
+        FlowMeter::wait_for_next_update(self).await
+    }
+}
 
-        FlowMeterTask {
-            signal,
-        }
+#[cfg(feature = "stm32")]
+struct FlowMeterTask<'a> {
+    signal: ExtiInput<'a, AnyPin>,
+}
+
+#[cfg(feature = "stm32")]
+impl<'a> FlowMeterTask<'a> {
+    /// Create a new `FlowMeterTask` instance.
+    pub fn new(signal: ExtiInput<'a, AnyPin>) -> Self {
+        FlowMeterTask { signal }
     }
 
     async fn wait_for_pulse(&mut self) {
         self.signal.wait_for_falling_edge().await;
     }
+}
 
+/// Converts the pulses of the flow meter into the amount of water they represent.
+///
+/// The amount of water per pulse depends on the flow rate, thus the converter keeps track
+/// of the pulses per second and compensates the amount accordingly.
+pub struct PulseConverter {
+    /// The smoothed number of pulses per second in 1/1000 pulses, such that the moving
+    /// average is not stuck below the actual rate due to the integer division.
+    milli_pulses_per_second: u32,
 }
 
-#[embassy_executor::task]
-async fn flowmeter_task() -> ! {
-    let mut flow_meter = FlowMeterTask::new();
-    let mut pulses_per_second: u32 = 0;
-
-    loop {
-        let before_pulse = Instant::now();
-        flow_meter.wait_for_pulse().await;
-        let pulse_duration = before_pulse.elapsed();
+impl PulseConverter {
+    /// Create a new `PulseConverter` assuming that no water is flowing.
+    pub const fn new() -> Self {
+        PulseConverter {
+            milli_pulses_per_second: 0,
+        }
+    }
+
+    /// The current (smoothed) number of pulses per second.
+    pub fn pulses_per_second(&self) -> u32 {
+        (self.milli_pulses_per_second + 500) / 1000
+    }
+
+    /// Register a pulse that was received `pulse_duration` after the previous one and get
+    /// the amount of water it represents.
+    pub fn on_pulse(&mut self, pulse_duration: Duration) -> Milligrams {
         if pulse_duration < Duration::from_secs(1) {
-            pulses_per_second = (4 * pulses_per_second + (1000 / pulse_duration.as_millis() as u32)) / 5;
+            let pulse_duration_ms = pulse_duration.as_millis().max(1) as u32;
+            let milli_pulses = 1_000_000 / pulse_duration_ms;
+            self.milli_pulses_per_second = (4 * self.milli_pulses_per_second + milli_pulses) / 5;
         } else {
-            pulses_per_second = 0;
+            self.milli_pulses_per_second = 0;
         }
+        Self::amount_per_pulse(self.pulses_per_second())
+    }
+
+    /// The flow rate if the flow meter is receiving `pulses_per_second` pulses.
+    pub fn flow_rate(pulses_per_second: u32) -> MlPerSecond {
+        let mg_per_second = pulses_per_second * Self::amount_per_pulse(pulses_per_second).get();
+        MlPerSecond::new(mg_per_second as f32 / 1000f32).unwrap_or(MlPerSecond::ZERO)
+    }
 
+    /// The amount of water a single pulse represents if the flow meter is
+    /// receiving `pulses_per_second` pulses.
+    pub fn amount_per_pulse(pulses_per_second: u32) -> Milligrams {
         /*
         Goal was to pour 50 g (theoretically 115 pulses with MG_PER_PULSE being 440) of water.
         These are the measurements for different pump speeds.
@@ -146,10 +232,112 @@ async fn flowmeter_task() -> ! {
         let correction_amount_mg = (174.408 - 18.575 * pulses_per_second as f32) as i32;
 
         // compensate
-        let amount_mg = MG_PER_PULSE as i32 - correction_amount_mg;
-        let new_amount_mg =
-            TOTAL_FLOW_IN_MG.fetch_add(amount_mg.try_into().unwrap(), portable_atomic::Ordering::SeqCst);
-        PULSE_CTR.add(1, portable_atomic::Ordering::SeqCst);
-        TOTAL_FLOW_IN_MG_SIGNAL.signal(new_amount_mg);
+        Milligrams::new((MG_PER_PULSE as i32 - correction_amount_mg).max(0) as u32)
+    }
+}
+
+/// Counts the pulses of a `FlowMeter` created via `FlowMeter::new_with_runner`.
+#[cfg(feature = "stm32")]
+pub struct FlowMeterRunner<'a> {
+    signal: ExtiInput<'a, AnyPin>,
+}
+
+#[cfg(feature = "stm32")]
+impl<'a> FlowMeterRunner<'a> {
+    /// Count the pulses of the flow meter and publish the amount of water flowed.
+    pub async fn run(self) -> ! {
+        let mut flow_meter = FlowMeterTask::new(self.signal);
+        let mut converter = PulseConverter::new();
+
+        loop {
+            let before_pulse = Instant::now();
+            flow_meter.wait_for_pulse().await;
+            let amount = converter.on_pulse(before_pulse.elapsed());
+
+            let new_amount = TOTAL_FLOW_IN_MG
+                .fetch_add(amount.get(), portable_atomic::Ordering::SeqCst)
+                .wrapping_add(amount.get());
+            PULSE_CTR.add(1, portable_atomic::Ordering::SeqCst);
+            PULSES_PER_SECOND.store(
+                converter.pulses_per_second(),
+                portable_atomic::Ordering::SeqCst,
+            );
+            LAST_PULSE_TICKS.store(Instant::now().as_ticks(), portable_atomic::Ordering::SeqCst);
+            TOTAL_FLOW_IN_MG_SIGNAL.signal(Milligrams::new(new_amount));
+            events::publish(MachineEvent::Flow(Milligrams::new(new_amount)));
+        }
+    }
+}
+
+#[cfg(feature = "stm32")]
+#[embassy_executor::task]
+async fn flowmeter_task(runner: FlowMeterRunner<'static>) -> ! {
+    runner.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amount_per_pulse_is_calibrated_for_9_pulses_per_second() {
+        let amount = PulseConverter::amount_per_pulse(9);
+        // The linear fit is off by a few mg at this point.
+        assert!(amount.get().abs_diff(MG_PER_PULSE) <= 10);
+    }
+
+    #[test]
+    fn amount_per_pulse_matches_measurements() {
+        // See the measurements in `PulseConverter::amount_per_pulse`.
+        assert_eq!(
+            PulseConverter::amount_per_pulse(5),
+            Milligrams::new(440 - 81)
+        );
+        assert_eq!(
+            PulseConverter::amount_per_pulse(13),
+            Milligrams::new(440 + 67)
+        );
+    }
+
+    #[test]
+    fn converter_settles_on_pulse_rate() {
+        let mut converter = PulseConverter::new();
+        for _ in 0..100 {
+            converter.on_pulse(Duration::from_millis(100));
+        }
+        assert_eq!(converter.pulses_per_second(), 10);
+        // The moving average also settles from above.
+        for _ in 0..100 {
+            converter.on_pulse(Duration::from_millis(200));
+        }
+        assert_eq!(converter.pulses_per_second(), 5);
+    }
+
+    #[test]
+    fn converter_resets_after_long_pause() {
+        let mut converter = PulseConverter::new();
+        converter.on_pulse(Duration::from_millis(50));
+        assert!(converter.pulses_per_second() > 0);
+        converter.on_pulse(Duration::from_secs(2));
+        assert_eq!(converter.pulses_per_second(), 0);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn on_pulse_never_panics(durations in proptest::collection::vec(0u64..5_000_000, 1..128)) {
+            let mut converter = PulseConverter::new();
+            for duration in durations {
+                let amount = converter.on_pulse(Duration::from_micros(duration));
+                proptest::prop_assert!(amount.get() >= MG_PER_PULSE - 175);
+            }
+        }
+
+        #[test]
+        fn amount_per_pulse_increases_with_rate(pulses_per_second in 0u32..100) {
+            proptest::prop_assert!(
+                PulseConverter::amount_per_pulse(pulses_per_second + 1)
+                    >= PulseConverter::amount_per_pulse(pulses_per_second)
+            );
+        }
     }
 }
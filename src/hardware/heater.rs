@@ -2,51 +2,127 @@
 //! Module to control the heater.
 //!
 
-use defmt::info;
-use embassy_executor::Spawner;
-use embassy_futures::select::{self, select};
-use embassy_stm32::{
-    gpio::{AnyPin, Level, Output, Pin, Speed},
-    Peripherals,
+use core::cell::Cell;
+
+use defmt::{info, warn};
+use embassy_executor::{SpawnError, Spawner};
+use embassy_futures::select;
+use embassy_stm32::gpio::{AnyPin, Level, Output, Pin, Speed};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
 };
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
-use embassy_time::{Duration, Ticker, Timer};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use portable_atomic::{AtomicU32, Ordering};
 
-static DUTY_CYCLE: Signal<ThreadModeRawMutex, u32> = Signal::new();
+use super::{
+    config::HeaterPin,
+    events::{self, Fault, MachineEvent},
+    flow_meter,
+    heater_modulation::{BurstFire, HeaterMode, SigmaDelta, DEFAULT_HEATER_MODE},
+    heater_safety::{HeaterTrip, SafetyLimits, SafetyMonitor, DEFAULT_SAFETY_LIMITS},
+    pump,
+    temperature::{self, HeaterCycle},
+    traits::HeaterActuator,
+};
+use crate::units::{MlPerSecond, PartsPerMillion, Percent};
 
+static DUTY_CYCLE: Signal<CriticalSectionRawMutex, PartsPerMillion> = Signal::new();
+static APPLIED_POWER: AtomicU32 = AtomicU32::new(0);
+static SAFETY_LIMITS: Mutex<CriticalSectionRawMutex, Cell<SafetyLimits>> =
+    Mutex::new(Cell::new(DEFAULT_SAFETY_LIMITS));
+static TRIP: Mutex<CriticalSectionRawMutex, Cell<Option<HeaterTrip>>> = Mutex::new(Cell::new(None));
+static RESET_TRIP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static HEATER_MODE: Mutex<CriticalSectionRawMutex, Cell<HeaterMode>> =
+    Mutex::new(Cell::new(DEFAULT_HEATER_MODE));
 
-pub struct Heater;
+/// The heater (thermoblock) of the machine used to heat the water.
+pub struct Heater {
+    _private: (),
+}
 
 impl Heater {
+    /// Create a new `Heater` instance that owns the `pin` switching the heater and
+    /// spawn the task driving the heater on `spawner`.
+    pub fn new(spawner: &Spawner, pin: HeaterPin) -> Result<Self, SpawnError> {
+        let (heater, runner) = Heater::new_with_runner(pin);
+        spawner.spawn(heater_task(runner))?;
 
-    /// Create a new `Heater` instance.
-    ///
-    /// # Safety
-    /// This is only safe to be called once.
-    ///
-    /// # Panics
-    /// If there is not enough memory to spawn a new task.
-    pub unsafe fn new(spawner: &mut Spawner) -> Self {
-        spawner.spawn(heater_task()).unwrap();
+        Ok(heater)
+    }
 
-        Heater {
+    /// Create a new `Heater` instance that owns the `pin` switching the heater without
+    /// spawning a task. The heater is only switched while the returned runner is running.
+    pub fn new_with_runner(pin: HeaterPin) -> (Self, HeaterRunner) {
+        (Heater { _private: () }, HeaterRunner { pin: pin.degrade() })
+    }
 
-        }
+    /// Set the power of the heater to `power`.
+    pub fn set_power(&mut self, power: Percent) {
+        DUTY_CYCLE.signal(power.into());
+    }
+
+    /// Set the power of the heater to the fractional `power`. The quantization error of each
+    /// period is carried over to the following periods, such that the average power matches
+    /// `power` precisely.
+    pub fn set_fractional_power(&mut self, power: PartsPerMillion) {
+        DUTY_CYCLE.signal(power);
+    }
+
+    /// Set how the power of the heater is modulated, `DEFAULT_HEATER_MODE` by default. Use
+    /// `HeaterMode::BurstFire` with the frequency of the local mains to switch the heater for
+    /// whole half-cycles only.
+    pub fn set_mode(&mut self, mode: HeaterMode) {
+        HEATER_MODE.lock(|heater_mode| heater_mode.set(mode));
+    }
+
+    /// How the power of the heater is modulated.
+    pub fn mode(&self) -> HeaterMode {
+        HEATER_MODE.lock(|heater_mode| heater_mode.get())
+    }
+
+    /// Set the limits the heater trips at, `DEFAULT_SAFETY_LIMITS` by default.
+    pub fn set_safety_limits(&mut self, limits: SafetyLimits) {
+        SAFETY_LIMITS.lock(|safety_limits| safety_limits.set(limits));
     }
 
-    pub fn set_power(&mut self, power_in_percent: u32) {
-        assert!(power_in_percent <= 100);
-        DUTY_CYCLE.signal(power_in_percent);
+    /// The limits the heater trips at.
+    pub fn safety_limits(&self) -> SafetyLimits {
+        SAFETY_LIMITS.lock(|safety_limits| safety_limits.get())
+    }
+
+    /// The reason the heater tripped, or `None` if it is not tripped. A tripped heater stays
+    /// off regardless of the requested power until `reset_trip` is called.
+    pub fn trip(&self) -> Option<HeaterTrip> {
+        TRIP.lock(|trip| trip.get())
+    }
+
+    /// Reset the trip of the heater. If its cause persists, the heater trips again.
+    pub fn reset_trip(&mut self) {
+        RESET_TRIP.signal(());
+    }
+}
+
+impl HeaterActuator for Heater {
+    fn set_power(&mut self, power: Percent) {
+        Heater::set_power(self, power);
+    }
+
+    fn set_fractional_power(&mut self, power: PartsPerMillion) {
+        Heater::set_fractional_power(self, power);
+    }
+
+    fn applied_power(&self) -> PartsPerMillion {
+        applied_power()
     }
 }
 
 impl Drop for Heater {
     fn drop(&mut self) {
-        DUTY_CYCLE.signal(0);
+        DUTY_CYCLE.signal(PartsPerMillion::ZERO);
     }
 }
 
-
 /// The heater used to boil the water.
 struct HeaterTask<'a> {
     pin: Output<'a, AnyPin>,
@@ -54,12 +130,8 @@ struct HeaterTask<'a> {
 
 impl<'a> HeaterTask<'a> {
     /// Create a new heater instance.
-    /// # Safety
-    /// This is only safe when called once and without concurrently calling any of the `new()``
-    /// methods of the other hardware components.
-    unsafe fn new() -> Self {
-        let p = Peripherals::steal();
-        let pin = Output::new(p.PB6.degrade(), Level::Low, Speed::Low);
+    fn new(pin: AnyPin) -> Self {
+        let pin = Output::new(pin, Level::Low, Speed::Low);
         HeaterTask { pin }
     }
 
@@ -80,36 +152,135 @@ impl<'a> Drop for HeaterTask<'a> {
     }
 }
 
-#[embassy_executor::task]
-async fn heater_task() -> ! {
-    let mut heater = unsafe { HeaterTask::new() };
-    heater.off();
-
-    let frequency = Duration::from_hz(10);
-    let mut ticker = Ticker::every(frequency);
-    let mut current_duty_cycle = None;
-
-    loop {
-        let new_duty_cycle = DUTY_CYCLE.wait();
-
-        match select::select(new_duty_cycle, ticker.next()).await {
-            select::Either::First(new_duty_cycle) => {
-                if new_duty_cycle > 0 {
-                    let duty_cycle_ms = frequency.as_millis() as f32 * (new_duty_cycle as f32 / 100f32);
-                    current_duty_cycle =  Some(duty_cycle_ms as u32);
-                } else {
-                    current_duty_cycle = None;
+/// The maximal power of the heater regardless of the requested power. A fault of the NTC
+/// forces the heater off, while an overheating board derates or disables it.
+fn power_limit() -> Percent {
+    if temperature::sensor_fault().is_some() {
+        Percent::ZERO
+    } else {
+        temperature::board_power_limit()
+    }
+}
+
+/// The power currently applied to the heater.
+pub(crate) fn applied_power() -> PartsPerMillion {
+    PartsPerMillion::new(APPLIED_POWER.load(Ordering::Relaxed)).unwrap_or(PartsPerMillion::MAX)
+}
+
+/// Drives the heater of a `Heater` created via `Heater::new_with_runner`.
+pub struct HeaterRunner {
+    pin: AnyPin,
+}
+
+impl HeaterRunner {
+    /// Switch the heater according to the power requested via the `Heater`.
+    /// The heater stays off while the NTC reports a fault or the heater is tripped, e.g.,
+    /// because it does not heat the water, and is restricted while the board is too hot.
+    /// Each switching cycle is announced to the temperature driver, such that the NTC is not
+    /// sampled on the switching edges.
+    pub async fn run(self) -> ! {
+        let mut heater = HeaterTask::new(self.pin);
+        heater.off();
+
+        let mut mode = HEATER_MODE.lock(|mode| mode.get());
+        let mut ticker = Ticker::every(mode.period());
+        let mut burst_fire = BurstFire::new();
+        let mut time_proportional = SigmaDelta::new();
+        let mut requested_power = PartsPerMillion::ZERO;
+        let mut safety = SafetyMonitor::new();
+
+        loop {
+            let new_duty_cycle = DUTY_CYCLE.wait();
+
+            match select::select(new_duty_cycle, ticker.next()).await {
+                select::Either::First(new_duty_cycle) => {
+                    requested_power = new_duty_cycle;
                 }
-            },
-            select::Either::Second(_) => {
-                if let Some(current_duty_cycle) = current_duty_cycle {
-                    heater.on();
-                    info!("current_duty_cycle={}", current_duty_cycle);
-                    Timer::after_millis(current_duty_cycle as u64).await;
-                    heater.off();
+                select::Either::Second(_) => {
+                    let new_mode = HEATER_MODE.lock(|mode| mode.get());
+                    if new_mode != mode {
+                        mode = new_mode;
+                        heater.off();
+                        ticker = Ticker::every(mode.period());
+                        burst_fire = BurstFire::new();
+                        time_proportional = SigmaDelta::new();
+                    }
+                    if RESET_TRIP.signaled() {
+                        RESET_TRIP.reset();
+                        if let Some(trip) = safety.trip() {
+                            safety.reset();
+                            TRIP.lock(|active_trip| active_trip.set(None));
+                            events::clear_fault(Fault::HeaterTrip(trip));
+                        }
+                    }
+                    let temperature = temperature::valid_temperature();
+                    let (_, pump_enabled) = pump::state();
+                    let water_flowing =
+                        pump_enabled || flow_meter::current_flow_rate() > MlPerSecond::ZERO;
+                    let limits = SAFETY_LIMITS.lock(|limits| limits.get());
+                    let tripped = safety.trip();
+                    let applied_power = safety.check(
+                        Instant::now(),
+                        requested_power.min(power_limit().into()),
+                        temperature,
+                        water_flowing,
+                        &limits,
+                    );
+                    if let (None, Some(trip)) = (tripped, safety.trip()) {
+                        warn!("Heater tripped: {}", trip);
+                        TRIP.lock(|active_trip| active_trip.set(Some(trip)));
+                        events::publish(MachineEvent::Fault(Fault::HeaterTrip(trip)));
+                    }
+                    APPLIED_POWER.store(applied_power.get(), Ordering::Relaxed);
+
+                    match mode {
+                        HeaterMode::TimeProportional => {
+                            let period = mode.period();
+                            let current_duty_cycle =
+                                time_proportional.next(applied_power, period.as_millis() as u32);
+                            temperature::set_heater_cycle(HeaterCycle {
+                                start: Instant::now(),
+                                on: Duration::from_millis(current_duty_cycle as u64),
+                                period,
+                                switched_each_period: false,
+                            });
+                            if current_duty_cycle > 0 {
+                                heater.on();
+                                info!("current_duty_cycle={}", current_duty_cycle);
+                                Timer::after_millis(current_duty_cycle as u64).await;
+                                heater.off();
+                            }
+                        }
+                        HeaterMode::BurstFire(mains) => {
+                            // The heater is only switched at the start of a half-cycle, which
+                            // drifts against the mains, see `heater_modulation`. Thus, the
+                            // samples must keep clear of the start of every half-cycle.
+                            let half_cycle = mains.half_cycle();
+                            let on = burst_fire.next(applied_power);
+                            temperature::set_heater_cycle(HeaterCycle {
+                                start: Instant::now(),
+                                on: if on {
+                                    half_cycle
+                                } else {
+                                    Duration::from_ticks(0)
+                                },
+                                period: half_cycle,
+                                switched_each_period: true,
+                            });
+                            if on {
+                                heater.on();
+                            } else {
+                                heater.off();
+                            }
+                        }
+                    }
                 }
-            },
+            }
         }
-
     }
-}
\ No newline at end of file
+}
+
+#[embassy_executor::task]
+async fn heater_task(runner: HeaterRunner) -> ! {
+    runner.run().await
+}
@@ -0,0 +1,176 @@
+//!
+//! Modulation of the heater power onto the switching of the heater.
+//!
+//! In the time-proportional mode, the heater is turned on for the fraction of a 100 ms period
+//! given by the power. This is simple, but switches the heater in the middle of the half-cycles
+//! of the mains.
+//!
+//! In the burst-fire mode, the heater is switched for whole half-cycles of the mains only. The
+//! half-cycles the heater is on are distributed evenly, such that each percent of power
+//! corresponds to exactly one of 100 consecutive half-cycles. Since the board cannot detect
+//! the zero crossings of the mains, the half-cycles are timed by a free-running timer of the
+//! firmware, which is not aligned to the mains. Its period also differs from the actual
+//! half-cycle by the tolerance of the mains frequency and the clock, such that the switching
+//! instants drift through the phase of the mains, e.g., through a whole half-cycle every 2.5 s
+//! at a deviation of 0.2 Hz from 50 Hz. Unless the heater is switched by a zero-crossing
+//! relay, which is not known for the original board, the heater is thus switched at arbitrary
+//! phase angles like in the time-proportional mode, though much less often. The average power
+//! is not affected, since each half-cycle carries the same energy wherever it starts.
+//!
+//! In both modes, the power is quantized by a `SigmaDelta` modulator, which carries the
+//! quantization error over to the following periods. Thus, the average power matches the
+//! requested power precisely, even if it is not a multiple of the resolution of a period.
+//!
+
+use embassy_time::Duration;
+
+use crate::units::PartsPerMillion;
+
+/// The period of the time-proportional mode.
+pub const TIME_PROPORTIONAL_PERIOD: Duration = Duration::from_millis(100);
+/// The mode the heater is switched in unless configured otherwise.
+pub const DEFAULT_HEATER_MODE: HeaterMode = HeaterMode::TimeProportional;
+
+/// The frequency of the mains the machine is connected to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MainsFrequency {
+    /// 50 Hz, e.g., in Europe.
+    Hz50,
+    /// 60 Hz, e.g., in North America.
+    Hz60,
+}
+
+impl MainsFrequency {
+    /// The duration of a half-cycle of the mains.
+    pub const fn half_cycle(self) -> Duration {
+        match self {
+            MainsFrequency::Hz50 => Duration::from_hz(100),
+            MainsFrequency::Hz60 => Duration::from_hz(120),
+        }
+    }
+}
+
+/// How the power of the heater is modulated, see the module documentation.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HeaterMode {
+    /// Turn the heater on for a fraction of `TIME_PROPORTIONAL_PERIOD`.
+    TimeProportional,
+    /// Turn the heater on for whole half-cycles of the mains with the given frequency.
+    BurstFire(MainsFrequency),
+}
+
+impl HeaterMode {
+    /// The interval the heater is switched in.
+    pub const fn period(self) -> Duration {
+        match self {
+            HeaterMode::TimeProportional => TIME_PROPORTIONAL_PERIOD,
+            HeaterMode::BurstFire(mains) => mains.half_cycle(),
+        }
+    }
+}
+
+/// First-order sigma-delta modulator quantizing a power to a number of steps per period.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct SigmaDelta {
+    /// The quantization error carried over from the previous periods in steps * 1/1_000_000.
+    error: i32,
+}
+
+impl SigmaDelta {
+    /// Create a new `SigmaDelta` without any carried over error.
+    pub const fn new() -> Self {
+        SigmaDelta { error: 0 }
+    }
+
+    /// The number of the `steps` of the next period the heater is on if it is driven with
+    /// `power`. At most 1000 steps per period are supported.
+    pub fn next(&mut self, power: PartsPerMillion, steps: u32) -> u32 {
+        let steps = steps.min(1000);
+        let target = (power.get() * steps) as i32 + self.error;
+        // The error is kept within -0.5..0.5 steps, thus the target is never below -0.5 steps.
+        let on = ((target + 500_000) / 1_000_000) as u32;
+        self.error = target - on as i32 * 1_000_000;
+        on
+    }
+}
+
+/// Decides which half-cycles the heater is on in the burst-fire mode.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct BurstFire {
+    modulator: SigmaDelta,
+}
+
+impl BurstFire {
+    /// Create a new `BurstFire`.
+    pub const fn new() -> Self {
+        BurstFire {
+            modulator: SigmaDelta::new(),
+        }
+    }
+
+    /// Whether the heater is on during the next half-cycle if it is driven with `power`.
+    pub fn next(&mut self, power: PartsPerMillion) -> bool {
+        self.modulator.next(power, 1) == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::Percent;
+
+    #[test]
+    fn half_cycles_match_mains() {
+        let micros = |mains: MainsFrequency| mains.half_cycle().as_micros();
+        assert!(micros(MainsFrequency::Hz50).abs_diff(10_000) < 100);
+        assert!(micros(MainsFrequency::Hz60).abs_diff(8_333) < 100);
+        assert_eq!(
+            HeaterMode::TimeProportional.period(),
+            TIME_PROPORTIONAL_PERIOD
+        );
+    }
+
+    #[test]
+    fn quantization_error_is_carried_over() {
+        let mut modulator = SigmaDelta::new();
+        // 12.34% cannot be represented by 100 steps per period.
+        let power = PartsPerMillion::new(123_400).unwrap();
+        let on: u32 = (0..50).map(|_| modulator.next(power, 100)).sum();
+        assert_eq!(on, 617);
+        assert!((0..50).all(|_| (12..=13).contains(&modulator.next(power, 100))));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn each_percent_is_one_half_cycle(power in 0u32..=100, start in 0usize..100) {
+            let mut burst_fire = BurstFire::new();
+            let power = Percent::new(power).unwrap();
+            for _ in 0..start {
+                burst_fire.next(power.into());
+            }
+            let pattern: Vec<bool> = (0..100).map(|_| burst_fire.next(power.into())).collect();
+
+            let on = pattern.iter().filter(|on| **on).count();
+            proptest::prop_assert_eq!(on as u8, power.get());
+            // The on half-cycles are spread evenly.
+            if power > Percent::ZERO {
+                let max_gap = 100u32.div_ceil(power.get() as u32) as usize;
+                let longest_off = pattern.split(|on| *on).map(|off| off.len()).max().unwrap();
+                proptest::prop_assert!(longest_off < 2 * max_gap);
+            }
+        }
+
+        #[test]
+        fn average_power_matches_request(power in 0u32..=1_000_000, steps in 1u32..=100) {
+            let mut modulator = SigmaDelta::new();
+            let power = PartsPerMillion::new(power).unwrap();
+            let periods = 1000u64;
+            let on: u64 = (0..periods).map(|_| modulator.next(power, steps) as u64).sum();
+            let requested = power.get() as u64 * steps as u64 * periods;
+            // The delivered energy deviates by less than one step in total.
+            proptest::prop_assert!((on * 1_000_000).abs_diff(requested) <= 500_000);
+        }
+    }
+}
@@ -0,0 +1,345 @@
+//!
+//! Safety limits enforced by the heater driver regardless of the power requested by the
+//! application, such that a bug in the control logic cannot boil the thermoblock dry.
+//!
+//! Exceeding a limit trips the heater, i.e., turns it off until the trip is reset explicitly.
+//!
+//! Besides the absolute limits, the heater trips if it is ineffective, i.e., if the commanded
+//! power does not heat the water (failed relay, NTC detached from the block, empty block). Like
+//! the thermal runaway protection of 3D-printer firmwares, the average power over a window is
+//! compared against the rise of the temperature over the same window. The check is suspended
+//! while water flows through the block, since the water carries the energy away.
+//!
+
+use embassy_time::{Duration, Instant};
+
+use crate::units::{MilliCelsius, PartsPerMillion, Percent};
+
+/// The power from which on the heater is considered to be driven with full power, such that
+/// requests just below full power do not evade `SafetyLimits::max_full_power`.
+pub const FULL_POWER_THRESHOLD: Percent = Percent::new(95).unwrap();
+
+/// The safety limits used by the heater unless configured otherwise.
+pub const DEFAULT_SAFETY_LIMITS: SafetyLimits = SafetyLimits {
+    max_temperature: MilliCelsius::new(140_000),
+    max_full_power: Duration::from_secs(120),
+    runaway: RunawayLimits {
+        window: Duration::from_secs(15),
+        min_power: Percent::new(50).unwrap(),
+        min_rise: MilliCelsius::new(3_000),
+    },
+};
+
+/// The limits the heater trips at.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SafetyLimits {
+    /// The water temperature above which the heater trips.
+    pub max_temperature: MilliCelsius,
+    /// The maximal time the heater may be driven with full power, i.e., at least
+    /// `FULL_POWER_THRESHOLD`, without interruption.
+    pub max_full_power: Duration,
+    /// The limits of the detection of an ineffective heater.
+    pub runaway: RunawayLimits,
+}
+
+/// The heater is considered ineffective if the temperature rises less than `min_rise` over a
+/// `window` in which the heater was driven with at least `min_power` on average.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RunawayLimits {
+    /// The window the power and the rise of the temperature are compared over.
+    pub window: Duration,
+    /// The average power above which the temperature must rise.
+    pub min_power: Percent,
+    /// The rise of the temperature expected at `min_power`.
+    pub min_rise: MilliCelsius,
+}
+
+/// The reason the heater tripped.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HeaterTrip {
+    /// The water temperature exceeded `SafetyLimits::max_temperature`.
+    OverTemperature,
+    /// The heater was driven with full power for longer than `SafetyLimits::max_full_power`.
+    FullPowerTimeout,
+    /// The heater did not heat the water as commanded, see `RunawayLimits`.
+    Ineffective,
+}
+
+/// The state of the current window of the `RunawayDetector`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct RunawayWindow {
+    start: Instant,
+    start_temperature: MilliCelsius,
+    /// The sum of the power of all updates within the window.
+    power_sum: u64,
+    updates: u64,
+}
+
+/// Detects an ineffective heater by comparing the commanded power against the rise of the
+/// temperature, see `RunawayLimits`. The power is expected to be updated at a fixed rate.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RunawayDetector {
+    window: Option<RunawayWindow>,
+}
+
+impl RunawayDetector {
+    /// Create a new `RunawayDetector`.
+    pub const fn new() -> Self {
+        RunawayDetector { window: None }
+    }
+
+    /// Discard the current window.
+    pub fn reset(&mut self) {
+        self.window = None;
+    }
+
+    /// Record that the heater is driven with `power` at `now` and the water has `temperature`,
+    /// which is `None` if it is unknown. Returns `true` if the heater was found to be
+    /// ineffective at the end of a window.
+    pub fn update(
+        &mut self,
+        now: Instant,
+        power: PartsPerMillion,
+        temperature: Option<MilliCelsius>,
+        water_flowing: bool,
+        limits: &RunawayLimits,
+    ) -> bool {
+        let Some(temperature) = temperature.filter(|_| !water_flowing) else {
+            self.window = None;
+            return false;
+        };
+        let window = self.window.get_or_insert(RunawayWindow {
+            start: now,
+            start_temperature: temperature,
+            power_sum: 0,
+            updates: 0,
+        });
+
+        if now.saturating_duration_since(window.start) < limits.window {
+            window.power_sum += power.get() as u64;
+            window.updates += 1;
+            return false;
+        }
+
+        let average_power = window.power_sum / window.updates.max(1);
+        let rise = temperature.get() - window.start_temperature.get();
+        self.window = None;
+        let min_power = PartsPerMillion::from(limits.min_power).get() as u64;
+        average_power >= min_power && rise < limits.min_rise.get()
+    }
+}
+
+impl Default for RunawayDetector {
+    fn default() -> Self {
+        RunawayDetector::new()
+    }
+}
+
+/// Checks the power applied to the heater against the `SafetyLimits` and latches the trip.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SafetyMonitor {
+    /// Since when the heater is driven with full power, if it is.
+    full_power_since: Option<Instant>,
+    runaway: RunawayDetector,
+    trip: Option<HeaterTrip>,
+}
+
+impl SafetyMonitor {
+    /// Create a new `SafetyMonitor` that is not tripped.
+    pub const fn new() -> Self {
+        SafetyMonitor {
+            full_power_since: None,
+            runaway: RunawayDetector::new(),
+            trip: None,
+        }
+    }
+
+    /// The reason the heater tripped, or `None` if it did not trip (since the last reset).
+    pub fn trip(&self) -> Option<HeaterTrip> {
+        self.trip
+    }
+
+    /// Reset the trip. If the cause of the trip persists, the heater trips again on the next
+    /// check.
+    pub fn reset(&mut self) {
+        self.full_power_since = None;
+        self.runaway.reset();
+        self.trip = None;
+    }
+
+    /// Check that `power` may be applied to the heater at `now` given the water `temperature`,
+    /// which is `None` if it is unknown, and whether water is flowing through the block.
+    /// Returns the power the heater may be driven with, which is zero while tripped or while
+    /// the temperature is unknown.
+    pub fn check(
+        &mut self,
+        now: Instant,
+        power: PartsPerMillion,
+        temperature: Option<MilliCelsius>,
+        water_flowing: bool,
+        limits: &SafetyLimits,
+    ) -> PartsPerMillion {
+        // Without a temperature, the heater cannot be protected against overheating.
+        let power = match temperature {
+            Some(_) => power,
+            None => PartsPerMillion::ZERO,
+        };
+        if self.trip.is_none() {
+            if temperature.is_some_and(|temperature| temperature > limits.max_temperature) {
+                self.trip = Some(HeaterTrip::OverTemperature);
+            } else if power >= FULL_POWER_THRESHOLD.into() {
+                let since = *self.full_power_since.get_or_insert(now);
+                if now.saturating_duration_since(since) >= limits.max_full_power {
+                    self.trip = Some(HeaterTrip::FullPowerTimeout);
+                }
+            } else {
+                self.full_power_since = None;
+            }
+        }
+        if self.trip.is_none() {
+            let runaway = &limits.runaway;
+            if self
+                .runaway
+                .update(now, power, temperature, water_flowing, runaway)
+            {
+                self.trip = Some(HeaterTrip::Ineffective);
+            }
+        }
+
+        if self.trip.is_some() {
+            PartsPerMillion::ZERO
+        } else {
+            power
+        }
+    }
+}
+
+impl Default for SafetyMonitor {
+    fn default() -> Self {
+        SafetyMonitor::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: SafetyLimits = SafetyLimits {
+        max_temperature: MilliCelsius::new(120_000),
+        max_full_power: Duration::from_secs(10),
+        runaway: RunawayLimits {
+            window: Duration::from_secs(30),
+            min_power: Percent::new(50).unwrap(),
+            min_rise: MilliCelsius::new(2_000),
+        },
+    };
+
+    fn check(monitor: &mut SafetyMonitor, secs: u64, power: Percent, celsius: i32) -> Percent {
+        let temperature = MilliCelsius::from_celsius(celsius);
+        let now = Instant::from_secs(secs);
+        let power = monitor.check(now, power.into(), temperature, false, &LIMITS);
+        power.to_percent()
+    }
+
+    #[test]
+    fn over_temperature_trips_until_reset() {
+        let mut monitor = SafetyMonitor::new();
+        let half = Percent::new(50).unwrap();
+
+        assert_eq!(check(&mut monitor, 0, half, 120), half);
+        assert_eq!(check(&mut monitor, 1, half, 121), Percent::ZERO);
+        assert_eq!(monitor.trip(), Some(HeaterTrip::OverTemperature));
+        // The trip is latched even after the water cooled down.
+        assert_eq!(check(&mut monitor, 2, half, 90), Percent::ZERO);
+
+        monitor.reset();
+        assert_eq!(check(&mut monitor, 3, half, 90), half);
+        assert_eq!(monitor.trip(), None);
+    }
+
+    #[test]
+    fn continuous_full_power_trips() {
+        let mut monitor = SafetyMonitor::new();
+        let full = Percent::MAX;
+
+        assert_eq!(check(&mut monitor, 0, full, 90), full);
+        assert_eq!(check(&mut monitor, 9, full, 90), full);
+        // Any interruption restarts the timeout.
+        let half = Percent::new(50).unwrap();
+        assert_eq!(check(&mut monitor, 10, half, 90), half);
+        assert_eq!(check(&mut monitor, 11, full, 90), full);
+        assert_eq!(check(&mut monitor, 20, full, 90), full);
+        assert_eq!(check(&mut monitor, 21, full, 90), Percent::ZERO);
+        assert_eq!(monitor.trip(), Some(HeaterTrip::FullPowerTimeout));
+        assert_eq!(check(&mut monitor, 22, Percent::ZERO, 90), Percent::ZERO);
+    }
+
+    #[test]
+    fn nearly_full_power_trips() {
+        let mut monitor = SafetyMonitor::new();
+        let temperature = MilliCelsius::from_celsius(90);
+        let almost = PartsPerMillion::new(999_999).unwrap();
+        let threshold = PartsPerMillion::from(FULL_POWER_THRESHOLD);
+        let mut check = |secs, power| {
+            let now = Instant::from_secs(secs);
+            monitor.check(now, power, temperature, false, &LIMITS)
+        };
+
+        assert_eq!(check(0, almost), almost);
+        // Dips that stay above the threshold do not restart the timeout.
+        assert_eq!(check(5, threshold), threshold);
+        assert_eq!(check(10, almost), PartsPerMillion::ZERO);
+        assert_eq!(monitor.trip(), Some(HeaterTrip::FullPowerTimeout));
+    }
+
+    #[test]
+    fn unknown_temperature_turns_heater_off() {
+        let mut monitor = SafetyMonitor::new();
+        let half = PartsPerMillion::from(Percent::new(50).unwrap());
+        let now = Instant::from_secs(0);
+
+        let power = monitor.check(now, half, None, false, &LIMITS);
+        assert_eq!(power, PartsPerMillion::ZERO);
+        assert_eq!(monitor.trip(), None);
+        let temperature = MilliCelsius::from_celsius(90);
+        assert_eq!(monitor.check(now, half, temperature, false, &LIMITS), half);
+    }
+
+    #[test]
+    fn ineffective_heater_trips() {
+        let mut detector = RunawayDetector::new();
+        let limits = LIMITS.runaway;
+        let power = PartsPerMillion::new(600_000).unwrap();
+        let mut update = |secs, celsius, water_flowing| {
+            let now = Instant::from_secs(secs);
+            let temperature = MilliCelsius::from_celsius(celsius);
+            detector.update(now, power, temperature, water_flowing, &limits)
+        };
+
+        // The temperature rises as expected.
+        assert!(!update(0, 40, false));
+        assert!(!update(15, 41, false));
+        assert!(!update(30, 42, false));
+        // Flowing water carries the energy away.
+        assert!(!update(31, 42, true));
+        assert!(!update(61, 42, true));
+        // The temperature stalls.
+        assert!(!update(62, 42, false));
+        assert!(!update(77, 43, false));
+        assert!(update(92, 43, false));
+    }
+
+    #[test]
+    fn low_power_does_not_require_a_rise() {
+        let mut detector = RunawayDetector::new();
+        let power = PartsPerMillion::new(100_000).unwrap();
+        let temperature = MilliCelsius::from_celsius(93);
+        for secs in 0..=60 {
+            let now = Instant::from_secs(secs);
+            assert!(!detector.update(now, power, temperature, false, &LIMITS.runaway));
+        }
+    }
+}
@@ -2,60 +2,58 @@
 //! Everything related to control the portafilter machine LED's.
 //!
 
-use embassy_executor::Spawner;
-use embassy_futures::select::{self};
-use embassy_stm32::{
-    gpio::{AnyPin, Level, Output, Speed},
-    Peripherals,
+use core::cell::Cell;
+use embassy_executor::{SpawnError, Spawner};
+use embassy_futures::{
+    join,
+    select::{self},
+};
+use embassy_stm32::gpio::{AnyPin, Level, Output, Speed};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
 };
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
 
 use embassy_stm32::gpio::Pin as _;
 use embassy_time::{Duration, Ticker};
 
-static ONE_CUP_LED_STATE: Signal<ThreadModeRawMutex, LEDState> = Signal::new();
-static TWO_CUP_LED_STATE: Signal<ThreadModeRawMutex, LEDState> = Signal::new();
+pub use super::traits::{LEDKind, LEDState};
+use super::{
+    config::{OneCupLedPin, TwoCupLedPin},
+    telemetry::LEDStates,
+};
 
-type Hz = u8;
+static ONE_CUP_LED_STATE: Signal<CriticalSectionRawMutex, LEDState> = Signal::new();
+static TWO_CUP_LED_STATE: Signal<CriticalSectionRawMutex, LEDState> = Signal::new();
+static LED_STATES: Mutex<CriticalSectionRawMutex, Cell<LEDStates>> =
+    Mutex::new(Cell::new(LEDStates::OFF));
 
-/// The state if an LED.
-#[derive(Clone, Copy)]
-pub enum LEDState {
-    /// LED is on.
-    On,
-    /// LED is off.
-    Off,
-    /// LED is blinking with the given frequency.
-    Blinking(Hz),
-}
-
-/// All the controllable LEDs.
-#[derive(Clone, Copy)]
-pub enum LEDKind {
-    /// The one cup button's LED.
-    OneCup,
-    /// The two cups button's LED.
-    TwoCup,
+/// All controllable LEDs of the machine.
+pub struct LEDs {
+    _private: (),
 }
 
-/// All controllable LEDs of the machine.
-pub struct LEDs;
 impl LEDs {
-    /// Cerate a new instance for controlling the LEDs.
-    /// # Panics
-    /// If there are insufficient ressource for spawning news tasks.
-    /// # Safety
-    /// This is only safe when called once and without concurrently calling any of the `new()``
-    /// methods of the other hardware components.
-    pub unsafe fn new(spawner: &mut Spawner) -> Self {
-        let p = unsafe { Peripherals::steal() };
-        spawner
-            .spawn(led_task(LEDKind::OneCup, p.PA15.degrade()))
-            .unwrap();
-        spawner
-            .spawn(led_task(LEDKind::TwoCup, p.PB3.degrade()))
-            .unwrap();
-        LEDs {}
+    /// Cerate a new instance for controlling the LEDs that owns the LED pins and
+    /// spawn the task driving the LEDs on `spawner`.
+    pub fn new(
+        spawner: &Spawner,
+        one_cup: OneCupLedPin,
+        two_cup: TwoCupLedPin,
+    ) -> Result<Self, SpawnError> {
+        let (leds, runner) = LEDs::new_with_runner(one_cup, two_cup);
+        spawner.spawn(led_task(runner))?;
+        Ok(leds)
+    }
+
+    /// Cerate a new instance for controlling the LEDs that owns the LED pins without
+    /// spawning a task. The LEDs are only updated while the returned runner is running.
+    pub fn new_with_runner(one_cup: OneCupLedPin, two_cup: TwoCupLedPin) -> (Self, LEDsRunner) {
+        let runner = LEDsRunner {
+            one_cup: one_cup.degrade(),
+            two_cup: two_cup.degrade(),
+        };
+        (LEDs { _private: () }, runner)
     }
 
     /// Turn all LEDs off.
@@ -100,13 +98,41 @@ impl<'a> LEDTask<'a> {
         self.led.toggle();
     }
 
+    #[allow(dead_code)]
     fn is_on(&self) -> bool {
         self.led.is_set_high()
     }
 }
 
-#[embassy_executor::task(pool_size = 2)]
-async fn led_task(kind: LEDKind, led: AnyPin) -> ! {
+/// Drives the LEDs of a `LEDs` instance created via `LEDs::new_with_runner`.
+pub struct LEDsRunner {
+    one_cup: AnyPin,
+    two_cup: AnyPin,
+}
+
+impl LEDsRunner {
+    /// Drive the LEDs according to the states requested via `LEDs`.
+    pub async fn run(self) -> ! {
+        let (never, _) = join::join(
+            drive_led(LEDKind::OneCup, self.one_cup),
+            drive_led(LEDKind::TwoCup, self.two_cup),
+        )
+        .await;
+        never
+    }
+}
+
+/// The states the LEDs are currently driven with.
+pub(crate) fn led_states() -> LEDStates {
+    LED_STATES.lock(|states| states.get())
+}
+
+#[embassy_executor::task]
+async fn led_task(runner: LEDsRunner) -> ! {
+    runner.run().await
+}
+
+async fn drive_led(kind: LEDKind, led: AnyPin) -> ! {
     let mut led = LEDTask::new(led);
     led.off();
 
@@ -119,20 +145,27 @@ async fn led_task(kind: LEDKind, led: AnyPin) -> ! {
     let mut ticker = Ticker::every(Duration::from_secs(3600));
     loop {
         match select::select(requested_led_state.wait(), ticker.next()).await {
-            select::Either::First(new_state) => match new_state {
-                LEDState::On => {
-                    ticker = Ticker::every(Duration::from_secs(3600));
-                    led.on();
-                }
-                LEDState::Off => {
-                    ticker = Ticker::every(Duration::from_secs(3600));
-                    led.off();
+            select::Either::First(new_state) => {
+                LED_STATES.lock(|states| {
+                    let mut new_states = states.get();
+                    new_states.set(kind, new_state);
+                    states.set(new_states);
+                });
+                match new_state {
+                    LEDState::On => {
+                        ticker = Ticker::every(Duration::from_secs(3600));
+                        led.on();
+                    }
+                    LEDState::Off => {
+                        ticker = Ticker::every(Duration::from_secs(3600));
+                        led.off();
+                    }
+                    LEDState::Blinking(frequency) => {
+                        blinking = true;
+                        ticker = Ticker::every(Duration::from_hz(frequency.get() as u64));
+                    }
                 }
-                LEDState::Blinking(frequency) => {
-                    blinking = true;
-                    ticker = Ticker::every(Duration::from_hz(frequency as u64));
-                }
-            },
+            }
             select::Either::Second(_) => {
                 if blinking {
                     led.toggle();
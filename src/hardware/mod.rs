@@ -2,11 +2,36 @@
 //! This module contains all functionality related to the different hardware components
 //! of the portafilter machine.
 //!
+//! The drivers require the `stm32` feature. Without it, only the hardware independent
+//! parts (traits, event types, conversions, ...) are available.
+//!
+//! Drivers that need to run in the background can either spawn their own task via `new()`,
+//! or be created via `new_with_runner()`, which returns the driver together with a runner.
+//! The `run()` future of the runner must then be polled by the application, e.g., by
+//! `join`ing it with other futures or by spawning it on an executor of its choice.
+//!
 
+#[cfg(feature = "stm32")]
+pub mod board;
 pub mod buttons;
+pub mod calibration;
+pub mod config;
+#[cfg(feature = "stm32")]
+pub mod error;
+pub mod events;
+pub mod filter;
 pub mod flow_meter;
+#[cfg(feature = "stm32")]
 pub mod heater;
+pub mod heater_modulation;
+pub mod heater_safety;
+#[cfg(feature = "stm32")]
 pub mod leds;
+pub mod ntc;
+#[cfg(feature = "stm32")]
 pub mod pump;
+#[cfg(feature = "stm32")]
 pub mod solenoid;
+pub mod telemetry;
 pub mod temperature;
+pub mod traits;
@@ -0,0 +1,217 @@
+//!
+//! Physical model of the NTC and the voltage divider it is part of, used to convert the
+//! raw ADC readings into a temperature.
+//!
+//! The resistance of the NTC is derived from the divider ratio measured by the ADC and then
+//! converted into a temperature via the Beta or the Steinhart–Hart equation of the thermistor.
+//!
+
+/// Offset between °C and K.
+const ZERO_CELSIUS_IN_KELVIN: f32 = 273.15;
+
+/// The model describing the resistance of the NTC over temperature.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NtcModel {
+    /// `1/T = 1/T0 + ln(R/R0)/beta` with `R0` being the resistance at `T0`.
+    Beta {
+        /// The resistance `R0` in Ω at `reference_celsius`.
+        reference_ohm: f32,
+        /// The temperature `T0` in °C `reference_ohm` is specified for, usually 25 °C.
+        reference_celsius: f32,
+        /// The Beta coefficient in K.
+        beta: f32,
+    },
+    /// `1/T = a + b * ln(R) + c * ln(R)^3`.
+    SteinhartHart {
+        /// The coefficient `a`.
+        a: f32,
+        /// The coefficient `b`.
+        b: f32,
+        /// The coefficient `c`.
+        c: f32,
+    },
+}
+
+impl NtcModel {
+    /// The temperature in °C at which the NTC has a resistance of `ohm`.
+    pub fn celsius(&self, ohm: f32) -> f32 {
+        let ln_r = libm::logf(ohm);
+        let inverse_kelvin = match *self {
+            NtcModel::Beta {
+                reference_ohm,
+                reference_celsius,
+                beta,
+            } => {
+                1.0 / (reference_celsius + ZERO_CELSIUS_IN_KELVIN)
+                    + (ln_r - libm::logf(reference_ohm)) / beta
+            }
+            NtcModel::SteinhartHart { a, b, c } => a + b * ln_r + c * ln_r * ln_r * ln_r,
+        };
+        1.0 / inverse_kelvin - ZERO_CELSIUS_IN_KELVIN
+    }
+
+    /// The resistance of the NTC in Ω at `celsius`.
+    pub fn resistance(&self, celsius: f32) -> f32 {
+        let inverse_kelvin = 1.0 / (celsius + ZERO_CELSIUS_IN_KELVIN);
+        match *self {
+            NtcModel::Beta {
+                reference_ohm,
+                reference_celsius,
+                beta,
+            } => {
+                reference_ohm
+                    * libm::expf(
+                        beta * (inverse_kelvin
+                            - 1.0 / (reference_celsius + ZERO_CELSIUS_IN_KELVIN)),
+                    )
+            }
+            NtcModel::SteinhartHart { a, b, c } => {
+                // Solve c * x^3 + b * x + (a - 1/T) = 0 for x = ln(R) via Cardano's formula.
+                let p = b / (3.0 * c);
+                let q = (a - inverse_kelvin) / (2.0 * c);
+                let root = libm::sqrtf(p * p * p + q * q);
+                libm::expf(libm::cbrtf(root - q) - libm::cbrtf(root + q))
+            }
+        }
+    }
+}
+
+/// The side of the voltage divider the NTC is placed on.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NtcPosition {
+    /// The NTC is connected to the supply and the divider resistor to ground,
+    /// i.e., the reading increases with the temperature.
+    HighSide,
+    /// The NTC is connected to ground and the divider resistor to the supply,
+    /// i.e., the reading decreases with the temperature.
+    LowSide,
+}
+
+/// The NTC together with the voltage divider and the ADC it is sampled with.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NtcParameters {
+    /// The model of the thermistor.
+    pub model: NtcModel,
+    /// The side of the divider the NTC is placed on.
+    pub position: NtcPosition,
+    /// The resistance of the fixed divider resistor in Ω.
+    pub divider_ohm: f32,
+    /// The voltage the divider is supplied with in mV.
+    pub divider_supply_mv: u32,
+    /// Whether the divider is supplied by the reference of the ADC, such that drift of the
+    /// supply cancels out in the readings. Otherwise, the readings are compensated for drift
+    /// of the reference of the ADC, which is measured via VREFINT.
+    pub ratiometric: bool,
+    /// The reference voltage of the ADC in mV.
+    pub adc_reference_mv: u32,
+    /// The raw value the ADC reads at its reference voltage.
+    pub adc_full_scale: u32,
+}
+
+impl NtcParameters {
+    /// The resistance of the NTC in Ω that results in the reading `raw`, or `None` if the
+    /// reading is at or beyond the rails of the divider, i.e., no finite resistance matches.
+    ///
+    /// `raw` is given in ADC counts and may have a fractional part, e.g., due to oversampling.
+    pub fn resistance(&self, raw: f32) -> Option<f32> {
+        let voltage = raw * self.adc_reference_mv as f32 / self.adc_full_scale as f32;
+        let supply = self.divider_supply_mv as f32;
+        if voltage <= 0.0 || voltage >= supply {
+            return None;
+        }
+
+        let ohm = match self.position {
+            NtcPosition::HighSide => self.divider_ohm * (supply - voltage) / voltage,
+            NtcPosition::LowSide => self.divider_ohm * voltage / (supply - voltage),
+        };
+        Some(ohm)
+    }
+
+    /// The temperature in °C that results in the reading `raw`, or `None` if the reading is
+    /// at or beyond the rails of the divider.
+    pub fn celsius(&self, raw: f32) -> Option<f32> {
+        self.resistance(raw).map(|ohm| self.model.celsius(ohm))
+    }
+
+    /// The reading in ADC counts expected at `celsius`, e.g., to derive thresholds or to
+    /// simulate the NTC.
+    pub fn raw(&self, celsius: f32) -> f32 {
+        let ohm = self.model.resistance(celsius);
+        let fraction = match self.position {
+            NtcPosition::HighSide => self.divider_ohm / (self.divider_ohm + ohm),
+            NtcPosition::LowSide => ohm / (self.divider_ohm + ohm),
+        };
+        let voltage = fraction * self.divider_supply_mv as f32;
+        voltage * self.adc_full_scale as f32 / self.adc_reference_mv as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A typical 10 kΩ thermistor in a divider with a 10 kΩ resistor.
+    const BETA: NtcParameters = NtcParameters {
+        model: NtcModel::Beta {
+            reference_ohm: 10_000.0,
+            reference_celsius: 25.0,
+            beta: 3950.0,
+        },
+        position: NtcPosition::LowSide,
+        divider_ohm: 10_000.0,
+        divider_supply_mv: 3300,
+        ratiometric: true,
+        adc_reference_mv: 3300,
+        adc_full_scale: 4095,
+    };
+
+    /// Steinhart–Hart coefficients of a 10 kΩ thermistor.
+    const STEINHART_HART: NtcModel = NtcModel::SteinhartHart {
+        a: 1.009_249_5e-3,
+        b: 2.378_405_4e-4,
+        c: 2.019_202_7e-7,
+    };
+
+    #[test]
+    fn beta_model_matches_reference_point() {
+        assert!((BETA.model.celsius(10_000.0) - 25.0).abs() < 0.01);
+        assert!((BETA.model.resistance(25.0) - 10_000.0).abs() < 1.0);
+        // Half of the supply at the reference temperature.
+        assert_eq!(BETA.celsius(2048.0).map(|c| c.round()), Some(25.0));
+    }
+
+    #[test]
+    fn readings_at_the_rails_have_no_resistance() {
+        assert_eq!(BETA.resistance(0.0), None);
+        assert_eq!(BETA.resistance(4095.0), None);
+        assert_eq!(BETA.resistance(5000.0), None);
+    }
+
+    #[test]
+    fn position_determines_direction() {
+        let high_side = NtcParameters {
+            position: NtcPosition::HighSide,
+            ..BETA
+        };
+        assert!(BETA.raw(90.0) < BETA.raw(20.0));
+        assert!(high_side.raw(90.0) > high_side.raw(20.0));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn models_are_invertible(celsius in 0f32..160.0) {
+            for model in [BETA.model, STEINHART_HART] {
+                let ohm = model.resistance(celsius);
+                proptest::prop_assert!((model.celsius(ohm) - celsius).abs() < 0.05);
+            }
+        }
+
+        #[test]
+        fn readings_round_trip(celsius in 0f32..160.0) {
+            let raw = BETA.raw(celsius).round();
+            let measured = BETA.celsius(raw).unwrap();
+            // The resolution of the ADC is worst at the ends of the range.
+            proptest::prop_assert!((measured - celsius).abs() < 0.5);
+        }
+    }
+}
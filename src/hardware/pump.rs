@@ -4,43 +4,39 @@
 
 use embassy_stm32::{
     gpio::OutputType,
-    peripherals::TIM16,
     time::Hertz,
     timer::{
         simple_pwm::{PwmPin, SimplePwm},
         Channel, CountingMode,
     },
-    Peripheral, Peripherals,
 };
 
+use portable_atomic::{AtomicBool, AtomicU8, Ordering};
+
+pub use super::traits::PumpPower;
+use super::{
+    config::{PumpPin, PumpTimer},
+    traits::PumpActuator,
+};
+use crate::units::Percent;
+
 const SPEED_LOWER_BOUND: u16 = 5;
 
-/// The power level of the pump.
-pub enum PumpPower {
-    /// Set pump to the lowest powerlevel that still allows to move the water.
-    Lowest,
-    /// Set the pump to the highest power level.
-    Highest,
-    /// Specific fraction of the maximal speed. This must be <= 1.
-    /// Setting this to 0.0 is equivalent to `Lowest` and 1.0 is the same as Highest.
-    Fraction(f32),
-}
+static DUTY_CYCLE: AtomicU8 = AtomicU8::new(0);
+static ENABLED: AtomicBool = AtomicBool::new(false);
 
 /// The water pump of the machine.
 pub struct Pump<'a, T> {
     pwm: SimplePwm<'a, T>,
 }
 
-impl<'a> Pump<'a, TIM16> {
-    /// Create a new `Pump` instance in order to controll the pump.
-    /// # Safety
-    /// This is only safe when called once and without concurrently calling any of the `new()``
-    /// methods of the other hardware components.
-    pub unsafe fn new() -> Self {
-        let p = unsafe { Peripherals::steal() };
-        let pin = PwmPin::new_ch1(unsafe { p.PB8.clone_unchecked() }, OutputType::PushPull);
+impl<'a> Pump<'a, PumpTimer> {
+    /// Create a new `Pump` instance in order to controll the pump via the PWM `pin`
+    /// driven by `timer`.
+    pub fn new(pin: PumpPin, timer: PumpTimer) -> Self {
+        let pin = PwmPin::new_ch1(pin, OutputType::PushPull);
         let pwm = SimplePwm::new(
-            unsafe { p.TIM16.clone_unchecked() },
+            timer,
             Some(pin),
             None,
             None,
@@ -69,18 +65,17 @@ impl<'a> Pump<'a, TIM16> {
             PumpPower::Lowest => self.pwm.set_duty(Channel::Ch1, SPEED_LOWER_BOUND),
             PumpPower::Highest => self.pwm.set_duty(Channel::Ch1, max_duty),
             PumpPower::Fraction(frac) => {
-                assert!(frac <= 1.0);
-                self.pwm.set_duty(
-                    Channel::Ch1,
-                    (frac * (max_duty as f32 - SPEED_LOWER_BOUND as f32)) as u16,
-                );
+                let range = (max_duty - SPEED_LOWER_BOUND) as u32;
+                self.pwm
+                    .set_duty(Channel::Ch1, (range * frac.get() as u32 / 100) as u16);
             }
         }
+        self.record_duty_cycle();
     }
 
     /// Turn the pump on if it is off, and vice versa.
     pub fn toggle(&mut self) {
-        if self.pwm.is_enabled(Channel::Ch1) {
+        if self.is_enabled() {
             self.disable();
         } else {
             self.enable();
@@ -90,11 +85,18 @@ impl<'a> Pump<'a, TIM16> {
     /// Turn the pump on.
     pub fn enable(&mut self) {
         self.pwm.enable(Channel::Ch1);
+        ENABLED.store(true, Ordering::Relaxed);
     }
 
     /// Turn the pump off.
     pub fn disable(&mut self) {
         self.pwm.disable(Channel::Ch1);
+        ENABLED.store(false, Ordering::Relaxed);
+    }
+
+    /// Check whether the pump is running.
+    pub fn is_enabled(&self) -> bool {
+        self.pwm.is_enabled(Channel::Ch1)
     }
 
     /// Get the maximum raw power value the is allowed to be passed to `set_raw_power()`.
@@ -110,5 +112,36 @@ impl<'a> Pump<'a, TIM16> {
         let max_duty = self.pwm.get_max_duty();
         assert!(power <= max_duty);
         self.pwm.set_duty(Channel::Ch1, power);
+        self.record_duty_cycle();
+    }
+
+    fn record_duty_cycle(&self) {
+        let duty =
+            self.pwm.get_duty(Channel::Ch1) as u32 * 100 / self.pwm.get_max_duty().max(1) as u32;
+        DUTY_CYCLE.store(duty.min(100) as u8, Ordering::Relaxed);
+    }
+}
+
+/// The duty cycle of the pump and whether it is running.
+pub(crate) fn state() -> (Percent, bool) {
+    let duty_cycle = Percent::new_saturating(DUTY_CYCLE.load(Ordering::Relaxed) as i32);
+    (duty_cycle, ENABLED.load(Ordering::Relaxed))
+}
+
+impl<'a> PumpActuator for Pump<'a, PumpTimer> {
+    fn set_power(&mut self, power: PumpPower) {
+        Pump::set_power(self, power);
+    }
+
+    fn enable(&mut self) {
+        Pump::enable(self);
+    }
+
+    fn disable(&mut self) {
+        Pump::disable(self);
+    }
+
+    fn is_enabled(&self) -> bool {
+        Pump::is_enabled(self)
     }
 }
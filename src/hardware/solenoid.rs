@@ -3,18 +3,14 @@
 //! that allows to switch the water flow to be poured via steam wand or shower.
 //!
 
-use embassy_stm32::{
-    gpio::{AnyPin, Level, Output, Pin, Speed},
-    Peripherals,
-};
-
-/// The ways water can be dispensed.
-pub enum WaterOutputKind {
-    /// via the shower head
-    Shower,
-    /// via the steam wand
-    SteamWand,
-}
+use embassy_stm32::gpio::{AnyPin, Level, Output, Pin, Speed};
+
+use portable_atomic::{AtomicBool, Ordering};
+
+pub use super::traits::WaterOutputKind;
+use super::{config::SolenoidPin, traits::WaterPath};
+
+static STEAM_WAND_SELECTED: AtomicBool = AtomicBool::new(false);
 
 /// Device to control whether water is flowing through the steam wand,
 /// or through the show.
@@ -25,12 +21,8 @@ pub struct Solenoid<'a> {
 impl<'a> Solenoid<'a> {
     /// Create a new `Solenoid` instance to control whether water is poured via shower or steam wand.
     /// By default, the the water is poured via the shower.
-    /// # Safety
-    /// This is only safe when called once and without concurrently calling any of the `new()``
-    /// methods of the other hardware components.
-    pub unsafe fn new() -> Self {
-        let p = unsafe { Peripherals::steal() };
-        let pin = p.PA11.degrade();
+    pub fn new(pin: SolenoidPin) -> Self {
+        let pin = pin.degrade();
         Solenoid {
             pin: Output::new(pin, Level::Low, Speed::Low),
         }
@@ -47,10 +39,27 @@ impl<'a> Solenoid<'a> {
     /// Dispense the water via the shower. This is the default mode.
     pub fn switch_to_shower(&mut self) {
         self.pin.set_low();
+        STEAM_WAND_SELECTED.store(false, Ordering::Relaxed);
     }
 
     /// Dispense the water (steam) via the steam wand.
     pub fn switch_to_steam_wand(&mut self) {
         self.pin.set_high();
+        STEAM_WAND_SELECTED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The way the water is currently dispensed.
+pub(crate) fn water_output() -> WaterOutputKind {
+    if STEAM_WAND_SELECTED.load(Ordering::Relaxed) {
+        WaterOutputKind::SteamWand
+    } else {
+        WaterOutputKind::Shower
+    }
+}
+
+impl<'a> WaterPath for Solenoid<'a> {
+    fn switch(&mut self, output: WaterOutputKind) {
+        Solenoid::switch(self, output);
     }
 }
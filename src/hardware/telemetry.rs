@@ -0,0 +1,277 @@
+//!
+//! Telemetry providing a consistent snapshot of the whole machine, e.g., for logging,
+//! a UI or debugging.
+//!
+//! The `Telemetry` driver periodically collects a [`MachineSnapshot`] from the state the
+//! other drivers record and publishes it to its subscribers.
+//!
+
+use embassy_time::Instant;
+
+use super::{
+    buttons::{ButtonKind, ButtonState},
+    events::ActiveFaults,
+    traits::{LEDKind, LEDState, WaterOutputKind},
+};
+use crate::units::{CelsiusPerSecond, MilliCelsius, Milligrams, MlPerSecond, Percent};
+#[cfg(feature = "stm32")]
+use {
+    super::{buttons, events, flow_meter, heater, leds, pump, solenoid, temperature},
+    embassy_executor::{SpawnError, Spawner},
+    embassy_sync::{
+        blocking_mutex::raw::CriticalSectionRawMutex,
+        pubsub::{self, PubSubChannel, Subscriber},
+    },
+    embassy_time::{Duration, Ticker},
+};
+
+/// Number of snapshots that are buffered per subscriber.
+#[cfg(feature = "stm32")]
+const SNAPSHOT_QUEUE_CAPACITY: usize = 2;
+/// Maximum number of snapshot subscribers that can be alive at the same time.
+#[cfg(feature = "stm32")]
+pub const MAX_SNAPSHOT_SUBSCRIBERS: usize = 2;
+
+#[cfg(feature = "stm32")]
+type SnapshotChannel = PubSubChannel<
+    CriticalSectionRawMutex,
+    MachineSnapshot,
+    SNAPSHOT_QUEUE_CAPACITY,
+    MAX_SNAPSHOT_SUBSCRIBERS,
+    0,
+>;
+
+/// A subscription to the periodically collected snapshots.
+#[cfg(feature = "stm32")]
+pub type SnapshotSubscriber = Subscriber<
+    'static,
+    CriticalSectionRawMutex,
+    MachineSnapshot,
+    SNAPSHOT_QUEUE_CAPACITY,
+    MAX_SNAPSHOT_SUBSCRIBERS,
+    0,
+>;
+
+#[cfg(feature = "stm32")]
+static SNAPSHOTS: SnapshotChannel = SnapshotChannel::new();
+
+/// The state of all buttons.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ButtonStates {
+    /// The one cup button.
+    pub one_cup: ButtonState,
+    /// The two cups button.
+    pub two_cup: ButtonState,
+    /// The hot water button.
+    pub hot_water: ButtonState,
+    /// The steam button.
+    pub steam: ButtonState,
+}
+
+impl ButtonStates {
+    /// All buttons released.
+    pub const RELEASED: ButtonStates = ButtonStates {
+        one_cup: ButtonState::Released,
+        two_cup: ButtonState::Released,
+        hot_water: ButtonState::Released,
+        steam: ButtonState::Released,
+    };
+
+    /// The state of `button`.
+    pub fn get(&self, button: ButtonKind) -> ButtonState {
+        match button {
+            ButtonKind::OneCup => self.one_cup,
+            ButtonKind::TwoCup => self.two_cup,
+            ButtonKind::HotWater => self.hot_water,
+            ButtonKind::Steam => self.steam,
+        }
+    }
+
+    /// Set the state of `button` to `state`.
+    pub fn set(&mut self, button: ButtonKind, state: ButtonState) {
+        match button {
+            ButtonKind::OneCup => self.one_cup = state,
+            ButtonKind::TwoCup => self.two_cup = state,
+            ButtonKind::HotWater => self.hot_water = state,
+            ButtonKind::Steam => self.steam = state,
+        }
+    }
+}
+
+/// The state of all LEDs.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LEDStates {
+    /// The one cup button's LED.
+    pub one_cup: LEDState,
+    /// The two cups button's LED.
+    pub two_cup: LEDState,
+}
+
+impl LEDStates {
+    /// All LEDs off.
+    pub const OFF: LEDStates = LEDStates {
+        one_cup: LEDState::Off,
+        two_cup: LEDState::Off,
+    };
+
+    /// The state of `led`.
+    pub fn get(&self, led: LEDKind) -> LEDState {
+        match led {
+            LEDKind::OneCup => self.one_cup,
+            LEDKind::TwoCup => self.two_cup,
+        }
+    }
+
+    /// Set the state of `led` to `state`.
+    pub fn set(&mut self, led: LEDKind, state: LEDState) {
+        match led {
+            LEDKind::OneCup => self.one_cup = state,
+            LEDKind::TwoCup => self.two_cup = state,
+        }
+    }
+}
+
+/// A snapshot of the state of the whole machine.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MachineSnapshot {
+    /// The time the snapshot was taken.
+    pub timestamp: Instant,
+    /// The raw ADC value of the NTC.
+    pub raw_temperature: u32,
+    /// The supply voltage of the ADC in mV.
+    pub adc_supply_mv: u32,
+    /// The water temperature.
+    pub temperature: MilliCelsius,
+    /// The rate of change of the water temperature.
+    pub temperature_rate: CelsiusPerSecond,
+    /// The temperature of the control board.
+    pub board_temperature: MilliCelsius,
+    /// The power currently applied to the heater.
+    pub heater_power: Percent,
+    /// The duty cycle of the pump.
+    pub pump_power: Percent,
+    /// Whether the pump is running.
+    pub pump_enabled: bool,
+    /// The way the water is dispensed.
+    pub water_output: WaterOutputKind,
+    /// The amount of water flowed so far.
+    pub flowed: Milligrams,
+    /// The current flow rate.
+    pub flow_rate: MlPerSecond,
+    /// The state of the buttons.
+    pub buttons: ButtonStates,
+    /// The state of the LEDs.
+    pub leds: LEDStates,
+    /// The faults that are currently active.
+    pub faults: ActiveFaults,
+}
+
+/// Collect a snapshot of the state recorded by the drivers.
+#[cfg(feature = "stm32")]
+pub fn capture() -> MachineSnapshot {
+    let (pump_power, pump_enabled) = pump::state();
+    MachineSnapshot {
+        timestamp: Instant::now(),
+        raw_temperature: temperature::raw_temperature(),
+        adc_supply_mv: temperature::adc_supply_mv(),
+        temperature: temperature::temperature(),
+        temperature_rate: temperature::temperature_rate(),
+        board_temperature: temperature::board_temperature(),
+        heater_power: heater::applied_power().to_percent(),
+        pump_power,
+        pump_enabled,
+        water_output: solenoid::water_output(),
+        flowed: flow_meter::total_flow(),
+        flow_rate: flow_meter::current_flow_rate(),
+        buttons: buttons::button_states(),
+        leds: leds::led_states(),
+        faults: events::active_faults(),
+    }
+}
+
+/// Periodically collects snapshots of the machine.
+#[cfg(feature = "stm32")]
+pub struct Telemetry {
+    _private: (),
+}
+
+#[cfg(feature = "stm32")]
+impl Telemetry {
+    /// Create a new `Telemetry` instance and spawn the task collecting a snapshot every
+    /// `period` on `spawner`.
+    pub fn new(spawner: &Spawner, period: Duration) -> Result<Self, SpawnError> {
+        let (telemetry, runner) = Telemetry::new_with_runner(period);
+        spawner.spawn(telemetry_task(runner))?;
+        Ok(telemetry)
+    }
+
+    /// Create a new `Telemetry` instance without spawning a task. Snapshots are only
+    /// collected every `period` while the returned runner is running.
+    pub fn new_with_runner(period: Duration) -> (Self, TelemetryRunner) {
+        (Telemetry { _private: () }, TelemetryRunner { period })
+    }
+
+    /// Collect a snapshot right now.
+    pub fn snapshot(&self) -> MachineSnapshot {
+        capture()
+    }
+
+    /// Subscribe to the periodically collected snapshots.
+    ///
+    /// Fails with [`pubsub::Error::MaximumSubscribersReached`] if there are already
+    /// `MAX_SNAPSHOT_SUBSCRIBERS` subscribers.
+    pub fn subscribe(&self) -> Result<SnapshotSubscriber, pubsub::Error> {
+        SNAPSHOTS.subscriber()
+    }
+}
+
+/// Collects the snapshots of a `Telemetry` created via `Telemetry::new_with_runner`.
+#[cfg(feature = "stm32")]
+pub struct TelemetryRunner {
+    period: Duration,
+}
+
+#[cfg(feature = "stm32")]
+impl TelemetryRunner {
+    /// Periodically collect a snapshot and publish it to the subscribers.
+    pub async fn run(self) -> ! {
+        let mut ticker = Ticker::every(self.period);
+        loop {
+            ticker.next().await;
+            SNAPSHOTS.immediate_publisher().publish_immediate(capture());
+        }
+    }
+}
+
+#[cfg(feature = "stm32")]
+#[embassy_executor::task]
+async fn telemetry_task(runner: TelemetryRunner) -> ! {
+    runner.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn button_states_are_set_per_button() {
+        let mut states = ButtonStates::RELEASED;
+        states.set(ButtonKind::Steam, ButtonState::Pressed);
+        for button in [ButtonKind::OneCup, ButtonKind::TwoCup, ButtonKind::HotWater] {
+            assert_eq!(states.get(button), ButtonState::Released);
+        }
+        assert_eq!(states.get(ButtonKind::Steam), ButtonState::Pressed);
+        assert_eq!(states.steam, ButtonState::Pressed);
+    }
+
+    #[test]
+    fn led_states_are_set_per_led() {
+        let mut states = LEDStates::OFF;
+        states.set(LEDKind::TwoCup, LEDState::On);
+        assert_eq!(states.get(LEDKind::OneCup), LEDState::Off);
+        assert_eq!(states.get(LEDKind::TwoCup), LEDState::On);
+    }
+}
@@ -3,114 +3,1122 @@
 //! to measure the water temperature just before it is exiting the heater.
 //!
 
-use core::num::NonZeroU16;
-
-use embassy_executor::{Executor, Spawner};
-use embassy_stm32::{
-    adc::{self, Adc},
-    bind_interrupts,
-    peripherals::{ADC, PB1},
-    Peripherals,
+use embassy_time::{Duration, Instant};
+
+use super::config::PROFILE;
+use crate::units::{CelsiusPerSecond, MilliCelsius, Percent};
+#[cfg(feature = "stm32")]
+use {
+    super::{
+        calibration::CalibrationTable,
+        config::{NtcAdc, NtcPin},
+        events::{self, Fault, MachineEvent},
+        filter::{FilterConfig, FilterPipeline, RateEstimator},
+        traits::TemperatureSensor,
+    },
+    core::cell::Cell,
+    embassy_executor::{SpawnError, Spawner},
+    embassy_stm32::{
+        adc::{self, Adc},
+        bind_interrupts,
+    },
+    embassy_sync::{
+        blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+        signal::Signal,
+    },
+    embassy_time::{Delay, TimeoutError, Timer},
+    portable_atomic::{AtomicBool, AtomicI32, AtomicU32},
 };
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
-use embassy_time::{Delay, Timer};
-use portable_atomic::AtomicU32;
 
-static RAW_TEMPERATURE_SIGNAL: Signal<ThreadModeRawMutex, u32> = Signal::new();
+/// Minimal interval between two temperature events on the machine event bus, such that
+/// the much faster sampling does not flood the bus.
+#[cfg(feature = "stm32")]
+const TEMPERATURE_EVENT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The interval the NTC is sampled in, unless the sample is shifted out of a switching edge
+/// of the heater.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+/// The maximal age of a reading the heater may be driven on. Older readings indicate that the
+/// NTC is no longer sampled.
+pub const MAX_READING_AGE: Duration = Duration::from_millis(3 * SAMPLE_INTERVAL.as_millis());
+/// The time around a switching edge of the heater during which the NTC is not sampled, such
+/// that the samples do not pick up switching transients.
+pub const SWITCHING_GUARD: Duration = Duration::from_millis(2);
+
+/// The window the rate of change of the temperature is estimated over by default.
+pub const DEFAULT_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// The lowest temperature the NTC may plausibly measure. Lower readings are classified
+/// as an open circuit.
+pub const MIN_PLAUSIBLE_TEMPERATURE: MilliCelsius = MilliCelsius::new(-20_000);
+/// The highest temperature the NTC may plausibly measure. Higher readings are classified
+/// as a short circuit.
+pub const MAX_PLAUSIBLE_TEMPERATURE: MilliCelsius = MilliCelsius::new(200_000);
+/// The fastest plausible change of the measured temperature in m°C per second.
+pub const MAX_TEMPERATURE_SLOPE: i32 = 50_000;
+/// Change between two readings that is always plausible, e.g., due to noise.
+const TEMPERATURE_STEP_MARGIN: i32 = 2_000;
+/// Number of consecutive valid readings required to clear a sensor fault.
+pub const FAULT_CLEAR_READINGS: u8 = 10;
+/// The supply voltage of the ADC in mV the factory calibration of VREFINT was taken at.
+const VREFINT_CAL_SUPPLY_MV: u32 = 3300;
+/// The typical reading of VREFINT (1.23 V) at `VREFINT_CAL_SUPPLY_MV`, used if the factory
+/// calibration is not available.
+const VREFINT_CAL_TYPICAL: u16 = 1526;
+/// The address of the factory calibration of VREFINT, see the datasheet of the STM32F070.
+#[cfg(feature = "stm32")]
+const VREFINT_CAL_ADDRESS: usize = 0x1FFF_F7BA;
+/// The temperature in °C the factory calibration of the internal temperature sensor was
+/// taken at, with the ADC supplied by `VREFINT_CAL_SUPPLY_MV`.
+const TS_CAL1_CELSIUS: f32 = 30.0;
+/// The typical reading of the internal temperature sensor (1.43 V) at `TS_CAL1_CELSIUS`, used
+/// if the factory calibration is not available.
+const TS_CAL1_TYPICAL: u16 = 1775;
+/// The typical slope of the internal temperature sensor in mV/°C.
+const TS_SLOPE_MV_PER_CELSIUS: f32 = 4.3;
+/// The address of the factory calibration of the internal temperature sensor.
+#[cfg(feature = "stm32")]
+const TS_CAL1_ADDRESS: usize = 0x1FFF_F7B8;
+/// The interval the board temperature is measured in.
+#[cfg(feature = "stm32")]
+const BOARD_TEMPERATURE_INTERVAL: Duration = Duration::from_secs(1);
+/// The amount the board temperature has to fall below the limit to clear the over-temperature
+/// fault again.
+pub const BOARD_TEMPERATURE_HYSTERESIS: MilliCelsius = MilliCelsius::new(5_000);
+/// The limit of the board temperature used by default.
+pub const DEFAULT_BOARD_TEMPERATURE_LIMIT: BoardTemperatureLimit = BoardTemperatureLimit {
+    limit: MilliCelsius::new(75_000),
+    action: OverTemperatureAction::Disable,
+};
+
+#[cfg(feature = "stm32")]
 static RAW_TEMPERATURE_C: AtomicU32 = AtomicU32::new(0);
+#[cfg(feature = "stm32")]
+static ADC_SUPPLY_MV: AtomicU32 = AtomicU32::new(PROFILE.ntc.adc_reference_mv);
+#[cfg(feature = "stm32")]
+static LAST_READING: Mutex<CriticalSectionRawMutex, Cell<TemperatureReading>> =
+    Mutex::new(Cell::new(TemperatureReading::NONE));
+#[cfg(feature = "stm32")]
+static HEATER_CYCLE: Mutex<CriticalSectionRawMutex, Cell<Option<HeaterCycle>>> =
+    Mutex::new(Cell::new(None));
+#[cfg(feature = "stm32")]
+static SENSOR_FAULT: Mutex<CriticalSectionRawMutex, Cell<Option<SensorFault>>> =
+    Mutex::new(Cell::new(Some(SensorFault::NoReading)));
+#[cfg(feature = "stm32")]
+static TEMPERATURE_UPDATE_SIGNAL: Signal<CriticalSectionRawMutex, TemperatureReading> =
+    Signal::new();
+#[cfg(feature = "stm32")]
+static FILTER_CONFIG: Mutex<CriticalSectionRawMutex, Cell<FilterConfig>> =
+    Mutex::new(Cell::new(FilterConfig::BREW));
+#[cfg(feature = "stm32")]
+static RATE_WINDOW: Mutex<CriticalSectionRawMutex, Cell<Duration>> =
+    Mutex::new(Cell::new(DEFAULT_RATE_WINDOW));
+#[cfg(feature = "stm32")]
+static CALIBRATION: Mutex<CriticalSectionRawMutex, Cell<CalibrationTable>> =
+    Mutex::new(Cell::new(CalibrationTable::EMPTY));
+#[cfg(feature = "stm32")]
+static BOARD_TEMPERATURE_MILLI_C: AtomicI32 = AtomicI32::new(0);
+#[cfg(feature = "stm32")]
+static BOARD_OVER_TEMPERATURE: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "stm32")]
+static BOARD_TEMPERATURE_LIMIT: Mutex<CriticalSectionRawMutex, Cell<BoardTemperatureLimit>> =
+    Mutex::new(Cell::new(DEFAULT_BOARD_TEMPERATURE_LIMIT));
+
+/// A fault of the NTC, detected by classifying its readings.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SensorFault {
+    /// No valid reading was taken yet, e.g., because the driver is not running.
+    NoReading,
+    /// The reading is below `MIN_PLAUSIBLE_TEMPERATURE`, e.g., because the NTC is disconnected.
+    OpenCircuit,
+    /// The reading is above `MAX_PLAUSIBLE_TEMPERATURE`, e.g., because the NTC is shorted.
+    ShortCircuit,
+    /// The reading changed faster than `MAX_TEMPERATURE_SLOPE`, e.g., due to a loose contact.
+    ImplausibleStep,
+}
+
+/// How the heater is restricted while the board temperature exceeds its limit.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OverTemperatureAction {
+    /// Limit the power of the heater to the given power.
+    Derate(Percent),
+    /// Turn the heater off.
+    Disable,
+}
+
+impl OverTemperatureAction {
+    /// The maximal power the heater may be driven with.
+    pub fn power_limit(&self) -> Percent {
+        match *self {
+            OverTemperatureAction::Derate(power) => power,
+            OverTemperatureAction::Disable => Percent::ZERO,
+        }
+    }
+}
+
+/// The limit of the temperature of the control board, measured by the internal temperature
+/// sensor of the MCU.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BoardTemperatureLimit {
+    /// The temperature above which the board is considered too hot.
+    pub limit: MilliCelsius,
+    /// How the heater is restricted while the board is too hot.
+    pub action: OverTemperatureAction,
+}
 
-pub struct Temperature;
+/// A temperature together with the point in time it was measured.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TemperatureReading {
+    /// The time the temperature was measured.
+    pub timestamp: Instant,
+    /// The measured temperature.
+    pub temperature: MilliCelsius,
+    /// The rate of change of the temperature, estimated over the recent readings.
+    pub rate: CelsiusPerSecond,
+    /// Whether the heater was on or off while the NTC was sampled.
+    pub heater_phase: HeaterPhase,
+}
 
+impl TemperatureReading {
+    /// Placeholder before the first reading, which is never valid.
+    pub const NONE: TemperatureReading = TemperatureReading {
+        timestamp: Instant::MIN,
+        temperature: MilliCelsius::new(0),
+        rate: CelsiusPerSecond::ZERO,
+        heater_phase: HeaterPhase::Off,
+    };
+
+    /// The temperature of this reading if it may be relied on at `now`, i.e., the sensor
+    /// reports no `fault` and the reading is at most `MAX_READING_AGE` old.
+    pub fn valid_temperature(
+        &self,
+        fault: Option<SensorFault>,
+        now: Instant,
+    ) -> Option<MilliCelsius> {
+        let age = now.saturating_duration_since(self.timestamp);
+        (fault.is_none() && age <= MAX_READING_AGE).then_some(self.temperature)
+    }
+}
+
+/// The phase of the switching cycle of the heater.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HeaterPhase {
+    /// The heater is turned on.
+    On,
+    /// The heater is turned off.
+    Off,
+}
+
+/// A switching cycle of the heater, which is turned on at `start` for `on` and then turned off
+/// for the remainder of `period`. The following cycles are assumed to have the same timing.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HeaterCycle {
+    /// The time the cycle started.
+    pub start: Instant,
+    /// The time the heater is on at the beginning of the cycle.
+    pub on: Duration,
+    /// The length of the cycle.
+    pub period: Duration,
+    /// Whether the heater may also be switched at the start of a cycle it is on or off for
+    /// entirely, as in the burst-fire mode.
+    pub switched_each_period: bool,
+}
+
+impl HeaterCycle {
+    /// The time that passed at `instant` since the start of the cycle `instant` falls into.
+    fn offset(&self, instant: Instant) -> u64 {
+        let elapsed = instant.as_ticks().saturating_sub(self.start.as_ticks());
+        elapsed % self.period.as_ticks().max(1)
+    }
+
+    /// The phase of the heater at `instant`.
+    pub fn phase_at(&self, instant: Instant) -> HeaterPhase {
+        if self.offset(instant) < self.on.as_ticks() {
+            HeaterPhase::On
+        } else {
+            HeaterPhase::Off
+        }
+    }
+
+    /// The first instant not before `instant` that is at least `guard` away from the switching
+    /// edges of the heater. Phases shorter than twice the guard are skipped.
+    pub fn quiet_instant(&self, instant: Instant, guard: Duration) -> Instant {
+        let (on, period, guard) = (self.on.as_ticks(), self.period.as_ticks(), guard.as_ticks());
+        if (on == 0 || on >= period) && !self.switched_each_period {
+            // The heater is not switched at all.
+            return instant;
+        }
+
+        let mut instant = instant;
+        // Each step moves past one edge, so the quiet instant is found within a single cycle.
+        for _ in 0..3 {
+            let offset = self.offset(instant);
+            let delay = if offset < guard {
+                guard - offset
+            } else if offset + guard > on && offset < on + guard {
+                on + guard - offset
+            } else if offset + guard > period {
+                period + guard - offset
+            } else {
+                return instant;
+            };
+            instant += Duration::from_ticks(delay);
+        }
+        instant
+    }
+}
+
+/// The temperature sensor (NTC) of the machine.
+#[cfg(feature = "stm32")]
+pub struct Temperature {
+    _private: (),
+}
+
+#[cfg(feature = "stm32")]
 impl Temperature {
+    /// Create a new `Temperature` instance that owns the `adc` and the `ntc_pin` the NTC is connected to
+    /// and spawn the task sampling the NTC on `spawner`.
+    pub fn new(spawner: &Spawner, adc: NtcAdc, ntc_pin: NtcPin) -> Result<Self, SpawnError> {
+        let (temperature, runner) = Temperature::new_with_runner(adc, ntc_pin);
+        spawner.spawn(temperature_task(runner))?;
 
-    /// Create a new `Temperature` instance.
+        Ok(temperature)
+    }
+
+    /// Create a new `Temperature` instance that owns the `adc` and the `ntc_pin` the NTC is connected to
+    /// without spawning a task. The temperature is only updated while the returned runner is running.
+    pub fn new_with_runner(adc: NtcAdc, ntc_pin: NtcPin) -> (Self, TemperatureRunner) {
+        (
+            Temperature { _private: () },
+            TemperatureRunner { adc, ntc_pin },
+        )
+    }
+
+    /// The last valid water temperature.
+    pub fn temperature(&self) -> MilliCelsius {
+        temperature()
+    }
+
+    /// The rate of change of the water temperature at the last valid reading.
+    pub fn rate(&self) -> CelsiusPerSecond {
+        temperature_rate()
+    }
+
+    /// Estimate the rate of change over the readings of the last `window` from the next
+    /// reading on. By default, the window is `DEFAULT_RATE_WINDOW`.
     ///
-    /// # Safety
-    /// This is only safe to be called once.
+    /// A longer window results in a smoother, but more delayed estimate.
+    pub fn set_rate_window(&mut self, window: Duration) {
+        RATE_WINDOW.lock(|rate_window| rate_window.set(window));
+    }
+
+    /// The window the rate of change is estimated over.
+    pub fn rate_window(&self) -> Duration {
+        RATE_WINDOW.lock(|rate_window| rate_window.get())
+    }
+
+    /// The current water temperature, or the fault of the NTC if its readings are not valid.
+    pub fn reading(&self) -> Result<MilliCelsius, SensorFault> {
+        match sensor_fault() {
+            Some(fault) => Err(fault),
+            None => Ok(temperature()),
+        }
+    }
+
+    /// The fault of the NTC, if any.
+    pub fn sensor_fault(&self) -> Option<SensorFault> {
+        sensor_fault()
+    }
+
+    /// Wait until the next valid reading of the NTC and return it. While the NTC reports a
+    /// fault, there are no valid readings.
     ///
-    /// # Panics
-    /// If there is not enough memory to spawn a new task.
-    pub unsafe fn new(spawner: &mut Spawner) -> Self {
-        spawner.spawn(temperature_task()).unwrap();
+    /// Only a single waiter is supported, use the machine event bus in order to observe
+    /// the temperature from multiple places.
+    pub async fn wait_for_update(&self) -> TemperatureReading {
+        TEMPERATURE_UPDATE_SIGNAL.wait().await
+    }
+
+    /// Wait until a reading above `threshold` is measured, e.g., until the water is hot
+    /// enough to brew. Fails if there is no such reading within `timeout`.
+    pub async fn wait_until_above(
+        &self,
+        threshold: MilliCelsius,
+        timeout: Duration,
+    ) -> Result<TemperatureReading, TimeoutError> {
+        TemperatureSensor::wait_until_above(self, threshold, timeout).await
+    }
+
+    /// Wait until a reading below `threshold` is measured, e.g., until the thermoblock
+    /// cooled down after steaming. Fails if there is no such reading within `timeout`.
+    pub async fn wait_until_below(
+        &self,
+        threshold: MilliCelsius,
+        timeout: Duration,
+    ) -> Result<TemperatureReading, TimeoutError> {
+        TemperatureSensor::wait_until_below(self, threshold, timeout).await
+    }
+
+    /// Filter the readings of the NTC according to `config` from the next reading on,
+    /// e.g., to switch between `FilterConfig::BREW` and `FilterConfig::STEAM`.
+    /// By default, the readings are filtered according to `FilterConfig::BREW`.
+    pub fn set_filter(&mut self, config: FilterConfig) {
+        FILTER_CONFIG.lock(|filter_config| filter_config.set(config));
+    }
+
+    /// The configuration the readings of the NTC are filtered with.
+    pub fn filter(&self) -> FilterConfig {
+        FILTER_CONFIG.lock(|filter_config| filter_config.get())
+    }
 
-        Temperature {
+    /// The last raw reading of the NTC in ADC counts, e.g., to calibrate the NTC.
+    pub fn raw_temperature(&self) -> u32 {
+        raw_temperature()
+    }
+
+    /// Correct the temperature converted via the NTC model of the board profile with `table`
+    /// from the next reading on. An empty table restores the uncorrected NTC model.
+    pub fn set_calibration(&mut self, table: CalibrationTable) {
+        CALIBRATION.lock(|calibration| calibration.set(table));
+    }
+
+    /// The table the temperature is currently corrected with.
+    pub fn calibration(&self) -> CalibrationTable {
+        CALIBRATION.lock(|calibration| calibration.get())
+    }
+
+    /// The supply voltage of the ADC in mV, measured via VREFINT alongside the NTC.
+    pub fn adc_supply_mv(&self) -> u32 {
+        adc_supply_mv()
+    }
 
+    /// The temperature of the control board, measured by the internal temperature sensor of
+    /// the MCU.
+    pub fn board_temperature(&self) -> MilliCelsius {
+        board_temperature()
+    }
+
+    /// Set the limit of the board temperature. Exceeding the limit raises a
+    /// `Fault::BoardOverTemperature` and restricts the heater according to `limit.action`
+    /// until the board cooled down by `BOARD_TEMPERATURE_HYSTERESIS`.
+    pub fn set_board_temperature_limit(&mut self, limit: BoardTemperatureLimit) {
+        BOARD_TEMPERATURE_LIMIT.lock(|board_limit| board_limit.set(limit));
+    }
+
+    /// The limit of the board temperature, `DEFAULT_BOARD_TEMPERATURE_LIMIT` by default.
+    pub fn board_temperature_limit(&self) -> BoardTemperatureLimit {
+        BOARD_TEMPERATURE_LIMIT.lock(|board_limit| board_limit.get())
+    }
+}
+
+/// Announce the switching cycle of the heater that just started, such that the NTC is sampled
+/// away from its switching edges.
+#[cfg(feature = "stm32")]
+pub(crate) fn set_heater_cycle(cycle: HeaterCycle) {
+    HEATER_CYCLE.lock(|heater_cycle| heater_cycle.set(Some(cycle)));
+}
+
+/// The last raw ADC value read from the NTC.
+#[cfg(feature = "stm32")]
+pub(crate) fn raw_temperature() -> u32 {
+    RAW_TEMPERATURE_C.load(portable_atomic::Ordering::Relaxed)
+}
+
+/// The supply voltage of the ADC in mV measured alongside the last reading of the NTC.
+#[cfg(feature = "stm32")]
+pub(crate) fn adc_supply_mv() -> u32 {
+    ADC_SUPPLY_MV.load(portable_atomic::Ordering::Relaxed)
+}
+
+/// The last temperature of the control board.
+#[cfg(feature = "stm32")]
+pub(crate) fn board_temperature() -> MilliCelsius {
+    MilliCelsius::new(BOARD_TEMPERATURE_MILLI_C.load(portable_atomic::Ordering::Relaxed))
+}
+
+/// The maximal power of the heater with respect to the board temperature.
+#[cfg(feature = "stm32")]
+pub(crate) fn board_power_limit() -> Percent {
+    if BOARD_OVER_TEMPERATURE.load(portable_atomic::Ordering::Relaxed) {
+        BOARD_TEMPERATURE_LIMIT.lock(|limit| limit.get().action.power_limit())
+    } else {
+        Percent::MAX
+    }
+}
+
+/// The last temperature converted from a valid reading of the NTC.
+#[cfg(feature = "stm32")]
+pub(crate) fn temperature() -> MilliCelsius {
+    LAST_READING.lock(|reading| reading.get().temperature)
+}
+
+/// The rate of change of the temperature at the last valid reading of the NTC.
+#[cfg(feature = "stm32")]
+pub(crate) fn temperature_rate() -> CelsiusPerSecond {
+    LAST_READING.lock(|reading| reading.get().rate)
+}
+
+/// The fault of the NTC, if any. The heater must stay off while a fault is active.
+#[cfg(feature = "stm32")]
+pub(crate) fn sensor_fault() -> Option<SensorFault> {
+    SENSOR_FAULT.lock(|fault| fault.get())
+}
+
+/// The last temperature if it may be relied on, see `TemperatureReading::valid_temperature`.
+/// `None` if the NTC is faulty, was not read yet or is no longer sampled.
+#[cfg(feature = "stm32")]
+pub(crate) fn valid_temperature() -> Option<MilliCelsius> {
+    let reading = LAST_READING.lock(|reading| reading.get());
+    reading.valid_temperature(sensor_fault(), Instant::now())
+}
+
+/// Report the change of the fault of the NTC from `previous` to `current` on the machine event
+/// bus. The previous fault is cleared once it is replaced by another fault or a valid reading.
+#[cfg(any(feature = "stm32", test))]
+fn report_sensor_fault(previous: Option<SensorFault>, current: Option<SensorFault>) {
+    use super::events::{self, Fault, MachineEvent};
+
+    if previous == current {
+        return;
+    }
+    if let Some(previous) = previous {
+        events::clear_fault(Fault::Sensor(previous));
+    }
+    if let Some(current) = current {
+        events::publish(MachineEvent::Fault(Fault::Sensor(current)));
+    }
+}
+
+/// Convert the `raw_value` read by the ADC into a temperature according to the NTC model of
+/// the board profile. `raw_value` may have a fractional part, e.g., due to oversampling.
+///
+/// Readings at the rails of the divider, e.g., caused by a disconnected NTC, are clamped to the
+/// closest reading the NTC can actually produce.
+pub fn raw_into_milli_celsius(raw_value: f32) -> MilliCelsius {
+    let ntc = PROFILE.ntc;
+    let full_scale = ntc.divider_supply_mv * ntc.adc_full_scale / ntc.adc_reference_mv;
+    let raw_value = raw_value.max(1.0).min((full_scale - 1) as f32);
+    ntc.celsius(raw_value)
+        .and_then(MilliCelsius::from_celsius_f32)
+        .unwrap_or_default()
+}
+
+/// Classifies the readings of the NTC into valid readings and sensor faults.
+///
+/// A fault is reported until `FAULT_CLEAR_READINGS` consecutive valid readings were taken.
+pub struct SensorMonitor {
+    open_circuit_raw: f32,
+    short_circuit_raw: f32,
+    last_temperature: Option<MilliCelsius>,
+    fault: Option<SensorFault>,
+    valid_readings: u8,
+}
+
+impl SensorMonitor {
+    /// Create a new `SensorMonitor` for the NTC of the board profile.
+    pub fn new() -> Self {
+        SensorMonitor {
+            open_circuit_raw: PROFILE.ntc.raw(MIN_PLAUSIBLE_TEMPERATURE.as_celsius_f32()),
+            short_circuit_raw: PROFILE.ntc.raw(MAX_PLAUSIBLE_TEMPERATURE.as_celsius_f32()),
+            last_temperature: None,
+            fault: None,
+            valid_readings: 0,
         }
     }
 
-    pub fn temperature_in_c(&self) -> u32 {
-        let raw_value = RAW_TEMPERATURE_C.load(portable_atomic::Ordering::Relaxed);
-        Temperature::raw_into_celsius(raw_value)
+    /// The currently reported fault, if any.
+    pub fn fault(&self) -> Option<SensorFault> {
+        self.fault
+    }
+
+    /// Classify the reading `raw`, which was taken `dt` after the previous one. Returns the
+    /// temperature, or the fault that is currently reported.
+    pub fn update(&mut self, raw: f32, dt: Duration) -> Result<MilliCelsius, SensorFault> {
+        let reading = self.classify(raw, dt);
+        match reading {
+            Err(fault) => {
+                self.fault = Some(fault);
+                self.valid_readings = 0;
+            }
+            Ok(_) if self.fault.is_some() => {
+                self.valid_readings += 1;
+                if self.valid_readings >= FAULT_CLEAR_READINGS {
+                    self.fault = None;
+                }
+            }
+            Ok(_) => {}
+        }
+
+        match self.fault {
+            Some(fault) => Err(fault),
+            None => reading,
+        }
+    }
+
+    fn classify(&mut self, raw: f32, dt: Duration) -> Result<MilliCelsius, SensorFault> {
+        // Depending on the side of the divider the NTC is on, the readings rise or fall
+        // with the temperature.
+        let (open_circuit, short_circuit) = if self.open_circuit_raw < self.short_circuit_raw {
+            (raw < self.open_circuit_raw, raw > self.short_circuit_raw)
+        } else {
+            (raw > self.open_circuit_raw, raw < self.short_circuit_raw)
+        };
+        if open_circuit || short_circuit {
+            self.last_temperature = None;
+            return Err(if open_circuit {
+                SensorFault::OpenCircuit
+            } else {
+                SensorFault::ShortCircuit
+            });
+        }
+
+        let temperature = raw_into_milli_celsius(raw);
+        if let Some(last_temperature) = self.last_temperature.replace(temperature) {
+            let step = (temperature.get() as i64 - last_temperature.get() as i64).abs();
+            let max_step = TEMPERATURE_STEP_MARGIN as i64
+                + MAX_TEMPERATURE_SLOPE as i64 * dt.as_millis() as i64 / 1000;
+            if step > max_step {
+                return Err(SensorFault::ImplausibleStep);
+            }
+        }
+        Ok(temperature)
     }
+}
 
-    fn raw_into_celsius(raw_value: u32) -> u32 {
-        /*
-        ADC Value -> Temperature
-        1000.0 -> 17
-        1339 -> 25
-        2064 -> 44
-        2997 -> 71
-        3341 -> 81
+/// Compensates the readings of the NTC for drift of the supply of the ADC, e.g., while the
+/// heater or the pump switches. The supply is derived from readings of the internal reference
+/// voltage VREFINT, which is independent of the supply.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SupplyCompensation {
+    vrefint_cal: u16,
+}
 
-        https://www.wolframalpha.com/input?i=quadratic+fit+calculator&assumption=%7B%22F%22%2C+%22QuadraticFitCalculator%22%2C+%22data2%22%7D+-%3E%22%7B%281000%2C+17%29%2C+%281339%2C25%29%2C+%282064%2C44%29%2C+%282997%2C71%29%2C+%283341%2C+81%29%7D%22
-        1.50104×10^-6 x^2 + 0.0209623 x - 5.59606
-        */
-        let raw_value = raw_value as f32;
-        let f1 = 1.50104f32 * (1f32 / (10u64.pow(6) as f32));
-        let result = f1 * (raw_value * raw_value) + 0.0209623f32 * raw_value - 5.59606f32;
-        result as u32
+impl SupplyCompensation {
+    /// Create a new `SupplyCompensation` based on `vrefint_cal`, the factory calibrated reading
+    /// of VREFINT. Falls back to the typical reading if the calibration is blank.
+    pub fn new(vrefint_cal: u16) -> Self {
+        let vrefint_cal = match vrefint_cal {
+            0 | u16::MAX => VREFINT_CAL_TYPICAL,
+            vrefint_cal => vrefint_cal,
+        };
+        SupplyCompensation { vrefint_cal }
+    }
+
+    /// The supply voltage of the ADC in mV derived from `vrefint_sum`, the sum of `samples`
+    /// readings of VREFINT, or `None` if the readings are not usable.
+    pub fn supply_mv(&self, vrefint_sum: u32, samples: u16) -> Option<u32> {
+        if vrefint_sum == 0 {
+            return None;
+        }
+        let supply_mv = VREFINT_CAL_SUPPLY_MV as u64 * self.vrefint_cal as u64 * samples as u64
+            / vrefint_sum as u64;
+        Some(supply_mv as u32)
     }
 
+    /// Scale `sum`, a sum of readings of the NTC taken at a supply of the ADC of `supply_mv`,
+    /// to the sum expected at the nominal reference voltage of the profile. Readings of a
+    /// ratiometric divider are returned unchanged.
+    pub fn compensate(&self, sum: u32, supply_mv: u32) -> u32 {
+        if PROFILE.ntc.ratiometric {
+            return sum;
+        }
+        (sum as u64 * supply_mv as u64 / PROFILE.ntc.adc_reference_mv as u64) as u32
+    }
 }
 
+/// Converts the readings of the internal temperature sensor of the MCU into the temperature
+/// of the control board and monitors it against a `BoardTemperatureLimit`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BoardMonitor {
+    ts_cal1: u16,
+    over_temperature: bool,
+}
 
+impl BoardMonitor {
+    /// Create a new `BoardMonitor` based on `ts_cal1`, the factory calibrated reading of the
+    /// internal temperature sensor. Falls back to the typical reading if the calibration is
+    /// blank.
+    pub fn new(ts_cal1: u16) -> Self {
+        let ts_cal1 = match ts_cal1 {
+            0 | u16::MAX => TS_CAL1_TYPICAL,
+            ts_cal1 => ts_cal1,
+        };
+        BoardMonitor {
+            ts_cal1,
+            over_temperature: false,
+        }
+    }
 
+    /// The board temperature derived from `sum`, the sum of `samples` readings of the internal
+    /// temperature sensor taken at a supply of the ADC of `supply_mv`.
+    pub fn temperature(&self, sum: u32, samples: u16, supply_mv: u32) -> MilliCelsius {
+        let full_scale = PROFILE.ntc.adc_full_scale as f32;
+        let sense_mv = sum as f32 * supply_mv as f32 / (samples.max(1) as f32 * full_scale);
+        let cal_mv = self.ts_cal1 as f32 * VREFINT_CAL_SUPPLY_MV as f32 / full_scale;
+        let celsius = TS_CAL1_CELSIUS + (cal_mv - sense_mv) / TS_SLOPE_MV_PER_CELSIUS;
+        MilliCelsius::from_celsius_f32(celsius).unwrap_or_default()
+    }
+
+    /// Whether the board is too hot.
+    pub fn over_temperature(&self) -> bool {
+        self.over_temperature
+    }
+
+    /// Check `temperature` against `limit` and return whether the board is too hot.
+    pub fn update(&mut self, temperature: MilliCelsius, limit: &BoardTemperatureLimit) -> bool {
+        if temperature > limit.limit {
+            self.over_temperature = true;
+        } else if temperature.get() < limit.limit.get() - BOARD_TEMPERATURE_HYSTERESIS.get() {
+            self.over_temperature = false;
+        }
+        self.over_temperature
+    }
+}
+
+#[cfg(feature = "stm32")]
+impl TemperatureSensor for Temperature {
+    fn temperature(&self) -> MilliCelsius {
+        Temperature::temperature(self)
+    }
+
+    fn sensor_fault(&self) -> Option<SensorFault> {
+        Temperature::sensor_fault(self)
+    }
+
+    async fn wait_for_update(&self) -> TemperatureReading {
+        Temperature::wait_for_update(self).await
+    }
+}
+
+#[cfg(feature = "stm32")]
 struct TemperatureTask<'a> {
-    adc: Adc<'a, ADC>,
-    ntc_pin: PB1,
+    adc: Adc<'a, NtcAdc>,
+    ntc_pin: NtcPin,
+    vref: adc::Vref,
+    internal_sensor: adc::Temperature,
 }
 
+#[cfg(feature = "stm32")]
 impl<'a> TemperatureTask<'a> {
-    /// Create a new `Temperature` instance in order to measure the water temperature.
-    /// # Safety
-    /// This is only safe when called once and without concurrently calling any of the `new()``
-    /// methods of the other hardware components.
-    unsafe fn new() -> Self {
-        let p = unsafe { Peripherals::steal() };
-
+    /// Create a new `TemperatureTask` instance in order to measure the water temperature.
+    fn new(adc: NtcAdc, ntc_pin: NtcPin) -> Self {
         bind_interrupts!(struct Irqs {
-            ADC1 => adc::InterruptHandler<ADC>;
+            ADC1 => adc::InterruptHandler<NtcAdc>;
         });
-        let mut adc = Adc::new(p.ADC, Irqs, &mut Delay);
-        // TODO: Check sample time.
-        adc.set_sample_time(adc::SampleTime::Cycles55_5);
-        let _vref = adc.enable_vref(&mut Delay);
+        let mut adc = Adc::new(adc, Irqs, &mut Delay);
+        // The internal temperature sensor and VREFINT require a sample time of at least 4 µs,
+        // i.e., 56 cycles of the 14 MHz ADC clock. The sample time is shared by all channels
+        // and VREFINT is read alternately with the NTC, thus it applies to the NTC as well.
+        adc.set_sample_time(adc::SampleTime::Cycles71_5);
+        let vref = adc.enable_vref(&mut Delay);
+        let internal_sensor = adc.enable_temperature(&mut Delay);
 
         TemperatureTask {
             adc,
-            ntc_pin: p.PB1,
+            ntc_pin,
+            vref,
+            internal_sensor,
+        }
+    }
+
+    /// Read the internal temperature sensor `samples` times and return the sum of the readings.
+    async fn read_internal_sensor(&mut self, samples: u16) -> u32 {
+        let mut sum: u32 = 0;
+        for _ in 0..samples {
+            sum += self.adc.read(&mut self.internal_sensor).await as u32;
         }
+        sum
     }
 
-    async fn read_raw_averaged(&mut self, iterations: NonZeroU16) -> u32 {
-        let mut mean: u32 = 0;
-        for _ in 0..iterations.get() {
-            let reading = self.adc.read(&mut self.ntc_pin).await;
-            mean += reading as u32;
+    /// Read the NTC `samples` times and return the sum of the readings together with the sum of
+    /// the readings of VREFINT. Both are read alternately, such that they see the same supply.
+    async fn read_oversampled(&mut self, samples: u16) -> (u32, u32) {
+        let mut sum: u32 = 0;
+        let mut vrefint_sum: u32 = 0;
+        for _ in 0..samples {
+            vrefint_sum += self.adc.read(&mut self.vref).await as u32;
+            sum += self.adc.read(&mut self.ntc_pin).await as u32;
         }
+        (sum, vrefint_sum)
+    }
+}
+
+/// Samples the NTC of a `Temperature` created via `Temperature::new_with_runner`.
+#[cfg(feature = "stm32")]
+pub struct TemperatureRunner {
+    adc: NtcAdc,
+    ntc_pin: NtcPin,
+}
 
-        mean / iterations.get() as u32
+#[cfg(feature = "stm32")]
+impl TemperatureRunner {
+    /// Periodically sample the NTC, filter the readings and publish the temperature, or the
+    /// fault if the readings are not valid. The samples are shifted out of the switching edges
+    /// of the heater by `SWITCHING_GUARD`.
+    pub async fn run(self) -> ! {
+        let mut task = TemperatureTask::new(self.adc, self.ntc_pin);
+        // SAFETY: The factory calibration is located in the read-only system memory.
+        let vrefint_cal = unsafe { core::ptr::read_volatile(VREFINT_CAL_ADDRESS as *const u16) };
+        let compensation = SupplyCompensation::new(vrefint_cal);
+        // SAFETY: See above.
+        let ts_cal1 = unsafe { core::ptr::read_volatile(TS_CAL1_ADDRESS as *const u16) };
+        let mut board_monitor = BoardMonitor::new(ts_cal1);
+        let mut last_board_reading = Instant::MIN;
+        let mut pipeline = FilterPipeline::new(FILTER_CONFIG.lock(|config| config.get()));
+        let mut monitor = SensorMonitor::new();
+        let mut estimator = RateEstimator::new(RATE_WINDOW.lock(|window| window.get()));
+        let mut last_reading = Instant::now();
+        let mut last_event = Instant::MIN;
+        let mut next_sample = Instant::now();
+
+        loop {
+            let config = FILTER_CONFIG.lock(|config| config.get());
+            if config != pipeline.config() {
+                pipeline.configure(config);
+            }
+            let rate_window = RATE_WINDOW.lock(|window| window.get());
+            if rate_window != estimator.window() {
+                estimator.set_window(rate_window);
+            }
+            let heater_cycle = HEATER_CYCLE.lock(|cycle| cycle.get());
+            if let Some(cycle) = heater_cycle {
+                next_sample = cycle.quiet_instant(next_sample, SWITCHING_GUARD);
+            }
+            Timer::at(next_sample).await;
+            let heater_phase =
+                heater_cycle.map_or(HeaterPhase::Off, |cycle| cycle.phase_at(Instant::now()));
+            let samples = config.samples_per_reading();
+            let (sum, vrefint_sum) = task.read_oversampled(samples).await;
+            let supply_mv = compensation
+                .supply_mv(vrefint_sum, samples)
+                .unwrap_or(PROFILE.ntc.adc_reference_mv);
+            ADC_SUPPLY_MV.store(supply_mv, portable_atomic::Ordering::Relaxed);
+            if last_board_reading.elapsed() >= BOARD_TEMPERATURE_INTERVAL {
+                last_board_reading = Instant::now();
+                let board_sum = task.read_internal_sensor(samples).await;
+                let board_temperature = board_monitor.temperature(board_sum, samples, supply_mv);
+                BOARD_TEMPERATURE_MILLI_C
+                    .store(board_temperature.get(), portable_atomic::Ordering::Relaxed);
+                let was_over_temperature = board_monitor.over_temperature();
+                let limit = BOARD_TEMPERATURE_LIMIT.lock(|limit| limit.get());
+                let over_temperature = board_monitor.update(board_temperature, &limit);
+                BOARD_OVER_TEMPERATURE.store(over_temperature, portable_atomic::Ordering::Relaxed);
+                if over_temperature && !was_over_temperature {
+                    events::publish(MachineEvent::Fault(Fault::BoardOverTemperature));
+                } else if !over_temperature && was_over_temperature {
+                    events::clear_fault(Fault::BoardOverTemperature);
+                }
+            }
+            let raw_temperature = pipeline.update(compensation.compensate(sum, supply_mv));
+            let dt = last_reading.elapsed();
+            last_reading = Instant::now();
+            RAW_TEMPERATURE_C.store(
+                libm::roundf(raw_temperature) as u32,
+                portable_atomic::Ordering::Relaxed,
+            );
+
+            let previous_fault = sensor_fault();
+            let reading = monitor.update(raw_temperature, dt);
+            SENSOR_FAULT.lock(|fault| fault.set(reading.err()));
+            if let Ok(temperature) = reading {
+                let temperature = CALIBRATION
+                    .lock(|calibration| calibration.get())
+                    .apply(raw_temperature, temperature);
+                let reading = TemperatureReading {
+                    timestamp: last_reading,
+                    temperature,
+                    rate: estimator.update(last_reading, temperature),
+                    heater_phase,
+                };
+                LAST_READING.lock(|last| last.set(reading));
+                TEMPERATURE_UPDATE_SIGNAL.signal(reading);
+                if last_event.elapsed() >= TEMPERATURE_EVENT_INTERVAL {
+                    last_event = Instant::now();
+                    events::publish(MachineEvent::Temperature(reading));
+                }
+            }
+            report_sensor_fault(previous_fault, reading.err());
+            next_sample = Instant::now() + SAMPLE_INTERVAL;
+        }
     }
 }
 
+#[cfg(feature = "stm32")]
 #[embassy_executor::task]
-async fn temperature_task() -> ! {
-    let mut task = unsafe { TemperatureTask::new() };
+async fn temperature_task(runner: TemperatureRunner) -> ! {
+    runner.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn abs_diff_celsius(temperature: MilliCelsius, celsius: i32) -> f32 {
+        (temperature.as_celsius_f32() - celsius as f32).abs()
+    }
+
+    #[test]
+    fn raw_into_milli_celsius_matches_calibration_points() {
+        // The model is fitted to these points, which are only accurate to about 2 °C.
+        for (raw, celsius) in [(1000, 17), (1339, 25), (2064, 44), (2997, 71), (3341, 81)] {
+            assert!(abs_diff_celsius(raw_into_milli_celsius(raw as f32), celsius) <= 2.5);
+        }
+    }
+
+    #[test]
+    fn raw_into_milli_celsius_covers_brew_and_steam_range() {
+        // Computed from the Beta equation (R0 = 22.8 kΩ at 25 °C, β = 4223) and the 10 kΩ
+        // divider resistor below the NTC: raw = 4095 * 10 kΩ / (R + 10 kΩ).
+        for (raw, celsius) in [(438.64, 0), (3517.26, 93), (3921.55, 140), (3985.06, 160)] {
+            let temperature = raw_into_milli_celsius(raw);
+            assert!(
+                abs_diff_celsius(temperature, celsius) <= 0.1,
+                "raw={} temperature={:?} expected={}",
+                raw,
+                temperature,
+                celsius
+            );
+        }
+    }
+
+    #[test]
+    fn raw_into_milli_celsius_resolves_fractions_of_a_degree() {
+        let brewing = PROFILE.ntc.raw(93.0);
+        let step =
+            raw_into_milli_celsius(brewing + 1.0).get() - raw_into_milli_celsius(brewing).get();
+        assert!(0 < step && step < 1000, "step={step}");
+    }
+
+    #[test]
+    fn raw_into_milli_celsius_is_signed() {
+        assert!(raw_into_milli_celsius(PROFILE.ntc.raw(-10.0)) < MilliCelsius::new(0));
+    }
+
+    const READING_INTERVAL: Duration = Duration::from_millis(20);
+
+    fn raw(celsius: f32) -> f32 {
+        PROFILE.ntc.raw(celsius)
+    }
+
+    #[test]
+    fn disconnected_and_shorted_ntc_are_detected() {
+        let full_scale = PROFILE.ntc.adc_full_scale as f32;
+        for (rail, fault) in [
+            (0.0, SensorFault::OpenCircuit),
+            (full_scale, SensorFault::ShortCircuit),
+        ] {
+            let mut monitor = SensorMonitor::new();
+            assert!(monitor.update(raw(20.0), READING_INTERVAL).is_ok());
+            assert_eq!(monitor.update(rail, READING_INTERVAL), Err(fault));
+            assert_eq!(monitor.fault(), Some(fault));
+        }
+    }
+
+    #[test]
+    fn spikes_are_implausible() {
+        let mut monitor = SensorMonitor::new();
+        assert!(monitor.update(raw(60.0), READING_INTERVAL).is_ok());
+        assert_eq!(
+            monitor.update(raw(90.0), READING_INTERVAL),
+            Err(SensorFault::ImplausibleStep)
+        );
+        // The same step is plausible over a longer period of time.
+        let mut monitor = SensorMonitor::new();
+        assert!(monitor.update(raw(60.0), READING_INTERVAL).is_ok());
+        assert!(monitor.update(raw(90.0), Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn fault_clears_after_valid_readings() {
+        let mut monitor = SensorMonitor::new();
+        assert!(monitor.update(0.0, READING_INTERVAL).is_err());
+        for _ in 1..FAULT_CLEAR_READINGS {
+            assert_eq!(
+                monitor.update(raw(20.0), READING_INTERVAL),
+                Err(SensorFault::OpenCircuit)
+            );
+        }
+        assert!(monitor.update(raw(20.0), READING_INTERVAL).is_ok());
+        assert_eq!(monitor.fault(), None);
+    }
 
-    loop {
-        let raw_temperature = task.read_raw_averaged(NonZeroU16::new(10).unwrap()).await;
-        RAW_TEMPERATURE_C.store(raw_temperature, portable_atomic::Ordering::Relaxed);
-        RAW_TEMPERATURE_SIGNAL.signal(raw_temperature);
-        Timer::after_millis(10).await;
+    #[test]
+    fn replaced_sensor_faults_are_cleared() {
+        use crate::hardware::events::{self, Fault, TEST_BUS};
+
+        let _bus = TEST_BUS.lock().unwrap();
+        let open_circuit = Some(SensorFault::OpenCircuit);
+        let short_circuit = Some(SensorFault::ShortCircuit);
+        report_sensor_fault(Some(SensorFault::NoReading), open_circuit);
+        report_sensor_fault(open_circuit, open_circuit);
+        report_sensor_fault(open_circuit, short_circuit);
+        let faults: Vec<_> = events::active_faults().iter().collect();
+        assert_eq!(faults, [Fault::Sensor(SensorFault::ShortCircuit)]);
+
+        report_sensor_fault(short_circuit, None);
+        assert!(events::active_faults().is_empty());
+    }
+
+    #[test]
+    fn stale_and_missing_readings_are_not_valid() {
+        let now = Instant::from_secs(60);
+        let temperature = MilliCelsius::new(93_000);
+        let reading = TemperatureReading {
+            timestamp: now,
+            temperature,
+            ..TemperatureReading::NONE
+        };
+        assert_eq!(reading.valid_temperature(None, now), Some(temperature));
+        let later = now + MAX_READING_AGE;
+        assert_eq!(reading.valid_temperature(None, later), Some(temperature));
+        // The runner stalled.
+        let stalled = later + Duration::from_millis(1);
+        assert_eq!(reading.valid_temperature(None, stalled), None);
+        let fault = Some(SensorFault::OpenCircuit);
+        assert_eq!(reading.valid_temperature(fault, now), None);
+        // No reading was taken yet.
+        let none = TemperatureReading::NONE;
+        let no_reading = Some(SensorFault::NoReading);
+        assert_eq!(none.valid_temperature(no_reading, now), None);
+        assert_eq!(none.valid_temperature(None, now), None);
+    }
+
+    #[test]
+    fn supply_is_derived_from_vrefint() {
+        let compensation = SupplyCompensation::new(1500);
+        assert_eq!(compensation.supply_mv(4 * 1500, 4), Some(3300));
+        assert_eq!(compensation.supply_mv(4 * 1650, 4), Some(3000));
+        assert_eq!(compensation.supply_mv(0, 4), None);
+        // A blank calibration falls back to the typical reading.
+        assert_eq!(
+            SupplyCompensation::new(u16::MAX),
+            SupplyCompensation::new(1526)
+        );
+    }
+
+    #[test]
+    fn compensated_readings_do_not_follow_supply() {
+        let compensation = SupplyCompensation::new(1500);
+        let nominal = PROFILE.ntc.raw(93.0);
+        for supply_mv in [3000, 3300, 3450] {
+            // The ADC reads the divider voltage relative to the drifting supply. A ratiometric
+            // divider drifts along with the supply.
+            let divider_mv = if PROFILE.ntc.ratiometric {
+                supply_mv
+            } else {
+                PROFILE.ntc.adc_reference_mv
+            };
+            let sum = (16.0 * nominal * divider_mv as f32 / supply_mv as f32) as u32;
+            let vrefint_sum = 16 * 1500 * VREFINT_CAL_SUPPLY_MV / supply_mv;
+            let supply_mv = compensation.supply_mv(vrefint_sum, 16).unwrap();
+            let raw = compensation.compensate(sum, supply_mv) as f32 / 16.0;
+            assert!(abs_diff_celsius(raw_into_milli_celsius(raw), 93) <= 0.1);
+        }
+    }
+
+    #[test]
+    fn board_temperature_matches_calibration() {
+        let monitor = BoardMonitor::new(1700);
+        let celsius = |sum, supply_mv| monitor.temperature(sum, 4, supply_mv).as_celsius_f32();
+        assert!((celsius(4 * 1700, 3300) - 30.0).abs() < 0.01);
+        // The sensor voltage falls with the temperature.
+        assert!((celsius(4 * 1500, 3300) - 67.5).abs() < 0.1);
+        // The same voltage results in a lower reading at a higher supply.
+        assert!((celsius(4 * 1650, 3400) - 30.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn board_over_temperature_has_hysteresis() {
+        let limit = BoardTemperatureLimit {
+            limit: MilliCelsius::new(70_000),
+            action: OverTemperatureAction::Derate(Percent::new(50).unwrap()),
+        };
+        let mut monitor = BoardMonitor::new(0);
+        assert!(!monitor.update(MilliCelsius::new(70_000), &limit));
+        assert!(monitor.update(MilliCelsius::new(70_001), &limit));
+        assert!(monitor.update(MilliCelsius::new(66_000), &limit));
+        assert!(!monitor.update(MilliCelsius::new(64_999), &limit));
+        assert_eq!(limit.action.power_limit(), Percent::new(50).unwrap());
+        assert_eq!(OverTemperatureAction::Disable.power_limit(), Percent::ZERO);
     }
-}
\ No newline at end of file
+
+    const HEATER_CYCLE: HeaterCycle = HeaterCycle {
+        start: Instant::from_millis(1000),
+        on: Duration::from_millis(30),
+        period: Duration::from_millis(100),
+        switched_each_period: false,
+    };
+
+    #[test]
+    fn heater_phase_follows_cycle() {
+        let at = |ms| HEATER_CYCLE.phase_at(Instant::from_millis(ms));
+        assert_eq!(at(1000), HeaterPhase::On);
+        assert_eq!(at(1029), HeaterPhase::On);
+        assert_eq!(at(1030), HeaterPhase::Off);
+        // The following cycles have the same timing.
+        assert_eq!(at(1515), HeaterPhase::On);
+        assert_eq!(at(1575), HeaterPhase::Off);
+    }
+
+    #[test]
+    fn samples_are_shifted_out_of_switching_edges() {
+        let quiet = |cycle: HeaterCycle, ms| {
+            let instant = cycle.quiet_instant(Instant::from_millis(ms), Duration::from_millis(2));
+            instant.as_millis()
+        };
+        assert_eq!(quiet(HEATER_CYCLE, 1010), 1010);
+        assert_eq!(quiet(HEATER_CYCLE, 1000), 1002);
+        assert_eq!(quiet(HEATER_CYCLE, 1029), 1032);
+        assert_eq!(quiet(HEATER_CYCLE, 1099), 1102);
+
+        // A phase too short for a sample is skipped.
+        let short = HeaterCycle {
+            on: Duration::from_millis(3),
+            ..HEATER_CYCLE
+        };
+        assert_eq!(quiet(short, 1000), 1005);
+        // Without switching, the samples are not shifted.
+        let off = HeaterCycle {
+            on: Duration::from_millis(0),
+            ..HEATER_CYCLE
+        };
+        assert_eq!(quiet(off, 1000), 1000);
+        // In the burst-fire mode, the heater may be switched at the start of any cycle.
+        let burst_fire = HeaterCycle {
+            on: HEATER_CYCLE.period,
+            switched_each_period: true,
+            ..HEATER_CYCLE
+        };
+        assert_eq!(quiet(burst_fire, 1050), 1050);
+        assert_eq!(quiet(burst_fire, 1099), 1102);
+        let burst_fire_off = HeaterCycle {
+            on: off.on,
+            ..burst_fire
+        };
+        assert_eq!(quiet(burst_fire_off, 1100), 1102);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn raw_into_milli_celsius_is_monotonic(raw in 0u32..4095) {
+            let raw = raw as f32;
+            proptest::prop_assert!(raw_into_milli_celsius(raw) <= raw_into_milli_celsius(raw + 1.0));
+        }
+
+        #[test]
+        fn plausible_readings_are_valid(start in 0f32..160.0, slopes in proptest::collection::vec(-20f32..20.0, 1..64)) {
+            let mut monitor = SensorMonitor::new();
+            let mut celsius = start;
+            for slope in slopes {
+                celsius = (celsius + slope * READING_INTERVAL.as_millis() as f32 / 1000.0).clamp(0.0, 160.0);
+                proptest::prop_assert!(monitor.update(raw(celsius), READING_INTERVAL).is_ok());
+            }
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//!
+//! Traits abstracting the actuators and sensors of the machine.
+//!
+//! The control logic is written against these traits instead of the concrete STM32
+//! drivers, such that it can also be driven by mocks or a simulator.
+//!
+#![allow(async_fn_in_trait)]
+
+use embassy_time::{with_timeout, Duration, TimeoutError};
+
+use super::temperature::{SensorFault, TemperatureReading};
+use crate::units::{Hertz, MilliCelsius, Milligrams, PartsPerMillion, Percent};
+
+/// The power level of the pump.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PumpPower {
+    /// Set pump to the lowest powerlevel that still allows to move the water.
+    Lowest,
+    /// Set the pump to the highest power level.
+    Highest,
+    /// Specific fraction of the maximal speed.
+    /// Setting this to 0% is equivalent to `Lowest` and 100% is the same as Highest.
+    Fraction(Percent),
+}
+
+/// The ways water can be dispensed.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum WaterOutputKind {
+    /// via the shower head
+    Shower,
+    /// via the steam wand
+    SteamWand,
+}
+
+/// The state if an LED.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LEDState {
+    /// LED is on.
+    On,
+    /// LED is off.
+    Off,
+    /// LED is blinking with the given frequency.
+    Blinking(Hertz),
+}
+
+/// All the controllable LEDs.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LEDKind {
+    /// The one cup button's LED.
+    OneCup,
+    /// The two cups button's LED.
+    TwoCup,
+}
+
+/// An actuator that heats the water, e.g., the thermoblock.
+pub trait HeaterActuator {
+    /// Set the power of the heater to `power`.
+    fn set_power(&mut self, power: Percent);
+
+    /// Set the power of the heater to the fractional `power`. Heaters that cannot apply
+    /// fractional power round it to the nearest percent.
+    fn set_fractional_power(&mut self, power: PartsPerMillion) {
+        self.set_power(power.to_percent());
+    }
+
+    /// The power currently applied to the heater. This is lower than the power that was set
+    /// while the heater is limited, e.g., because it tripped.
+    fn applied_power(&self) -> PartsPerMillion;
+}
+
+/// An actuator that moves the water, e.g., the vibratory pump.
+pub trait PumpActuator {
+    /// Set the power of the pump to `power`.
+    fn set_power(&mut self, power: PumpPower);
+
+    /// Turn the pump on.
+    fn enable(&mut self);
+
+    /// Turn the pump off.
+    fn disable(&mut self);
+
+    /// Check whether the pump is running.
+    fn is_enabled(&self) -> bool;
+}
+
+/// An actuator that selects the way the water is dispensed.
+pub trait WaterPath {
+    /// Switch the water to be dispensed via `output`.
+    fn switch(&mut self, output: WaterOutputKind);
+}
+
+/// A sensor measuring the water temperature.
+pub trait TemperatureSensor {
+    /// The current temperature.
+    fn temperature(&self) -> MilliCelsius;
+
+    /// The fault of the sensor, if any. While a fault is active, `temperature()` must not be
+    /// used to control the heater. There is deliberately no default, since a sensor that is
+    /// assumed to be healthy without checking keeps the heater running.
+    fn sensor_fault(&self) -> Option<SensorFault>;
+
+    /// Wait until the next valid reading of the sensor and return it.
+    async fn wait_for_update(&self) -> TemperatureReading;
+
+    /// Wait until a reading above `threshold` is measured. Fails if there is no such reading
+    /// within `timeout`.
+    async fn wait_until_above(
+        &self,
+        threshold: MilliCelsius,
+        timeout: Duration,
+    ) -> Result<TemperatureReading, TimeoutError> {
+        with_timeout(timeout, async {
+            loop {
+                let reading = self.wait_for_update().await;
+                if reading.temperature > threshold {
+                    return reading;
+                }
+            }
+        })
+        .await
+    }
+
+    /// Wait until a reading below `threshold` is measured. Fails if there is no such reading
+    /// within `timeout`.
+    async fn wait_until_below(
+        &self,
+        threshold: MilliCelsius,
+        timeout: Duration,
+    ) -> Result<TemperatureReading, TimeoutError> {
+        with_timeout(timeout, async {
+            loop {
+                let reading = self.wait_for_update().await;
+                if reading.temperature < threshold {
+                    return reading;
+                }
+            }
+        })
+        .await
+    }
+}
+
+/// A sensor measuring the amount of water that has been pumped.
+pub trait FlowSensor {
+    /// The amount of water flowed so far.
+    fn flowed(&self) -> Milligrams;
+
+    /// Wait until the flowed amount received an update and return the new value.
+    async fn wait_for_next_update(&self) -> Milligrams;
+
+    /// Wait until the specified `amount` of water has been poured.
+    async fn wait_for_amount(&self, amount: Milligrams) {
+        let start = self.flowed();
+        loop {
+            let new_value = self.wait_for_next_update().await;
+            if new_value.wrapping_sub(start) >= amount {
+                return;
+            }
+        }
+    }
+}
@@ -1,8 +1,7 @@
 //!
 //! A library used to control the hardware of an Sage/Breville Bambino (BES450) portafilter machine.
 //!
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
 #![allow(clippy::new_without_default)]
 #![warn(clippy::cognitive_complexity, missing_docs)]
 #![deny(
@@ -13,5 +12,10 @@
     clippy::semicolon_if_nothing_returned
 )]
 
+#[cfg(all(feature = "stm32", feature = "host"))]
+compile_error!("The features `stm32` and `host` are mutually exclusive.");
+
 pub mod hardware;
 pub mod logic;
+pub mod simulator;
+pub mod units;
@@ -0,0 +1,312 @@
+//!
+//! Runtime that runs controllers, e.g., the temperature PID, at fixed rates.
+//!
+//! Each controller is driven by its own `Ticker`, such that the period does not drift, and
+//! gets the exact time since its previous iteration passed. If a controller is woken up too
+//! late to meet one or more of its deadlines, the missed iterations are skipped and counted
+//! as overruns. The worst-case jitter, execution time and the number of overruns are recorded
+//! in a [`LoopMetrics`] instance per controller that can be read while the loop is running.
+//!
+
+use embassy_futures::select::select_array;
+use embassy_time::{Duration, Instant, Ticker};
+use portable_atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// A controller that is run periodically by a [`ControlLoop`].
+pub trait Controller {
+    /// Run a single iteration of the controller. `dt` is the time since the previous
+    /// iteration, which is the period of the controller unless iterations were skipped.
+    fn update(&mut self, dt: Duration);
+}
+
+impl<F: FnMut(Duration)> Controller for F {
+    fn update(&mut self, dt: Duration) {
+        self(dt);
+    }
+}
+
+/// Timing metrics of a controller run by a [`ControlLoop`].
+pub struct LoopMetrics {
+    iterations: AtomicU32,
+    overruns: AtomicU32,
+    max_jitter_ticks: AtomicU64,
+    max_execution_ticks: AtomicU64,
+}
+
+impl LoopMetrics {
+    /// Create new, empty metrics. This is `const` such that the metrics can be placed in a `static`.
+    pub const fn new() -> Self {
+        LoopMetrics {
+            iterations: AtomicU32::new(0),
+            overruns: AtomicU32::new(0),
+            max_jitter_ticks: AtomicU64::new(0),
+            max_execution_ticks: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of iterations the controller was run.
+    pub fn iterations(&self) -> u32 {
+        self.iterations.load(Ordering::Relaxed)
+    }
+
+    /// Number of iterations that were skipped because the controller was run too late.
+    pub fn overruns(&self) -> u32 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+
+    /// The maximal delay between the deadline of an iteration and the point in time
+    /// the controller was actually run.
+    pub fn max_jitter(&self) -> Duration {
+        Duration::from_ticks(self.max_jitter_ticks.load(Ordering::Relaxed))
+    }
+
+    /// The maximal time a single iteration of the controller took.
+    pub fn max_execution_time(&self) -> Duration {
+        Duration::from_ticks(self.max_execution_ticks.load(Ordering::Relaxed))
+    }
+
+    /// Reset all metrics to zero.
+    pub fn reset(&self) {
+        self.iterations.store(0, Ordering::Relaxed);
+        self.overruns.store(0, Ordering::Relaxed);
+        self.max_jitter_ticks.store(0, Ordering::Relaxed);
+        self.max_execution_ticks.store(0, Ordering::Relaxed);
+    }
+
+    fn record(&self, tick: &Tick, execution_time: Duration) {
+        self.iterations.add(1, Ordering::Relaxed);
+        self.overruns.add(tick.missed, Ordering::Relaxed);
+        self.max_jitter_ticks
+            .fetch_max(tick.jitter.as_ticks(), Ordering::Relaxed);
+        self.max_execution_ticks
+            .fetch_max(execution_time.as_ticks(), Ordering::Relaxed);
+    }
+}
+
+/// An iteration of a controller as determined by a [`Schedule`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Tick {
+    /// The time since the previous iteration.
+    pub dt: Duration,
+    /// The delay between the deadline of this iteration and the time it was run.
+    pub jitter: Duration,
+    /// Number of deadlines that were missed and are skipped.
+    pub missed: u32,
+}
+
+/// The deadlines of a controller that should be run every `period`.
+pub struct Schedule {
+    period: Duration,
+    deadline: Instant,
+}
+
+impl Schedule {
+    /// Create a new schedule whose first deadline is one `period` after `start`.
+    pub fn new(start: Instant, period: Duration) -> Self {
+        Schedule {
+            period,
+            deadline: start + period,
+        }
+    }
+
+    /// The period of the schedule.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// The next deadline.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Register that the controller is run at `now` and advance to the next deadline.
+    /// Deadlines that already passed at `now` are skipped.
+    pub fn on_tick(&mut self, now: Instant) -> Tick {
+        let jitter = now.saturating_duration_since(self.deadline);
+        let missed = (jitter.as_ticks() / self.period.as_ticks().max(1)) as u32;
+        let dt = self.period * (missed + 1);
+        self.deadline += dt;
+        Tick { dt, jitter, missed }
+    }
+}
+
+/// A controller together with the rate it is run at and the metrics it records.
+pub struct Periodic<'a> {
+    controller: &'a mut dyn Controller,
+    period: Duration,
+    metrics: &'a LoopMetrics,
+}
+
+impl<'a> Periodic<'a> {
+    /// Run `controller` every `period` and record its timing in `metrics`.
+    pub fn new(
+        period: Duration,
+        controller: &'a mut dyn Controller,
+        metrics: &'a LoopMetrics,
+    ) -> Self {
+        Periodic {
+            controller,
+            period,
+            metrics,
+        }
+    }
+}
+
+/// Runs `N` controllers at their fixed rates.
+pub struct ControlLoop<'a, const N: usize> {
+    controllers: [Periodic<'a>; N],
+}
+
+impl<'a, const N: usize> ControlLoop<'a, N> {
+    /// Create a new loop running `controllers`.
+    pub fn new(controllers: [Periodic<'a>; N]) -> Self {
+        ControlLoop { controllers }
+    }
+
+    /// Run the controllers. The first iteration of each controller happens one period
+    /// after calling this function.
+    pub async fn run(&mut self) -> ! {
+        let start = Instant::now();
+        let mut tickers = self.controllers.each_ref().map(|c| Ticker::every(c.period));
+        let mut schedules = self
+            .controllers
+            .each_ref()
+            .map(|c| Schedule::new(start, c.period));
+
+        loop {
+            let (_, index) = select_array(tickers.each_mut().map(|t| t.next())).await;
+            let tick = schedules[index].on_tick(Instant::now());
+            // The ticker would catch up on the missed deadlines immediately, so skip them.
+            for _ in 0..tick.missed {
+                tickers[index].next().await;
+            }
+
+            let periodic = &mut self.controllers[index];
+            let begin = Instant::now();
+            periodic.controller.update(tick.dt);
+            periodic.metrics.record(&tick, begin.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at_ms(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn on_time_ticks_pass_the_period() {
+        let mut schedule = Schedule::new(at_ms(0), Duration::from_millis(50));
+        for i in 1..10 {
+            let tick = schedule.on_tick(at_ms(50 * i));
+            assert_eq!(tick.dt, Duration::from_millis(50));
+            assert_eq!(tick.jitter, Duration::from_ticks(0));
+            assert_eq!(tick.missed, 0);
+        }
+    }
+
+    #[test]
+    fn late_ticks_skip_missed_deadlines() {
+        let mut schedule = Schedule::new(at_ms(0), Duration::from_millis(50));
+        let tick = schedule.on_tick(at_ms(53));
+        assert_eq!(tick.jitter, Duration::from_millis(3));
+        assert_eq!(tick.missed, 0);
+
+        let tick = schedule.on_tick(at_ms(215));
+        assert_eq!(tick.jitter, Duration::from_millis(115));
+        assert_eq!(tick.missed, 2);
+        assert_eq!(tick.dt, Duration::from_millis(150));
+        assert_eq!(schedule.deadline(), at_ms(250));
+    }
+
+    #[test]
+    fn metrics_record_worst_case() {
+        let metrics = LoopMetrics::new();
+        let mut schedule = Schedule::new(at_ms(0), Duration::from_millis(10));
+        for now in [12, 20, 47, 50] {
+            let tick = schedule.on_tick(at_ms(now));
+            metrics.record(&tick, Duration::from_millis(now % 7));
+        }
+
+        assert_eq!(metrics.iterations(), 4);
+        assert_eq!(metrics.overruns(), 1);
+        assert_eq!(metrics.max_jitter(), Duration::from_millis(17));
+        assert_eq!(metrics.max_execution_time(), Duration::from_millis(6));
+
+        metrics.reset();
+        assert_eq!(metrics.iterations(), 0);
+        assert_eq!(metrics.max_jitter(), Duration::from_ticks(0));
+    }
+
+    #[test]
+    fn runs_controllers_at_their_rates() {
+        use core::{cell::Cell, future::poll_fn, task::Poll};
+
+        let fast_period = Duration::from_millis(20);
+        let slow_period = Duration::from_millis(50);
+        let fast_time = Cell::new(Duration::from_ticks(0));
+        let slow_time = Cell::new(Duration::from_ticks(0));
+        let mut fast = |dt: Duration| {
+            assert_eq!(dt.as_ticks() % fast_period.as_ticks(), 0);
+            fast_time.set(fast_time.get() + dt);
+        };
+        let mut slow = |dt: Duration| {
+            assert_eq!(dt.as_ticks() % slow_period.as_ticks(), 0);
+            slow_time.set(slow_time.get() + dt);
+        };
+        let (fast_metrics, slow_metrics) = (LoopMetrics::new(), LoopMetrics::new());
+        let mut control_loop = ControlLoop::new([
+            Periodic::new(fast_period, &mut fast, &fast_metrics),
+            Periodic::new(slow_period, &mut slow, &slow_metrics),
+        ]);
+
+        // Stop once the slow controller covered four periods instead of after a fixed time,
+        // such that the result does not depend on the load of the machine running the test.
+        // This is polled whenever one of the controllers was woken up.
+        let slow_done = poll_fn(|_| {
+            if slow_time.get() >= slow_period * 4 {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        });
+        let begin = Instant::now();
+        embassy_futures::block_on(embassy_futures::select::select(
+            control_loop.run(),
+            slow_done,
+        ));
+        let elapsed = begin.elapsed();
+
+        // Late iterations skip deadlines, but the time passed to the controllers always covers
+        // the iterations and skipped deadlines recorded in the metrics.
+        let (fast_time, slow_time) = (fast_time.get(), slow_time.get());
+        let fast_periods = fast_metrics.iterations() + fast_metrics.overruns();
+        let slow_periods = slow_metrics.iterations() + slow_metrics.overruns();
+        assert_eq!(fast_time, fast_period * fast_periods);
+        assert_eq!(slow_time, slow_period * slow_periods);
+        // The fast controller is due at the same time as the slow one and is run first.
+        assert!(fast_time >= slow_period * 4, "fast_time={:?}", fast_time);
+        // No deadline is run before it passed.
+        assert!(fast_time <= elapsed && slow_time <= elapsed);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn deadlines_stay_in_phase(period_ms in 1u64..100, lateness in proptest::collection::vec(0u64..300, 1..32)) {
+            let period = Duration::from_millis(period_ms);
+            let mut schedule = Schedule::new(at_ms(0), period);
+            let mut total = Duration::from_ticks(0);
+            for lateness in lateness {
+                let now = schedule.deadline() + Duration::from_millis(lateness);
+                let tick = schedule.on_tick(now);
+                total += tick.dt;
+                proptest::prop_assert!(schedule.deadline() > now);
+                proptest::prop_assert_eq!(tick.dt, period * (tick.missed + 1));
+            }
+            proptest::prop_assert_eq!(schedule.deadline(), at_ms(0) + total + period);
+        }
+    }
+}
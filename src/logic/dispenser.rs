@@ -0,0 +1,81 @@
+//!
+//! Logic to dispense a given amount of water by stopping the pump once the flow meter
+//! measured the requested amount.
+//!
+
+use crate::{
+    hardware::traits::{FlowSensor, PumpActuator, PumpPower},
+    units::Milligrams,
+};
+
+/// Dispenses a fixed amount of water and stops the pump afterwards.
+pub struct Dispenser {
+    start: Milligrams,
+    amount: Milligrams,
+    active: bool,
+}
+
+impl Dispenser {
+    /// Create a new, idle `Dispenser`.
+    pub fn new() -> Self {
+        Dispenser {
+            start: Milligrams::ZERO,
+            amount: Milligrams::ZERO,
+            active: false,
+        }
+    }
+
+    /// Start the `pump` with `power` in order to pour `amount` of water as measured by `flow`.
+    /// The pump is stopped by a subsequent call to `update()` once the amount has been poured.
+    pub fn start<P: PumpActuator, F: FlowSensor>(
+        &mut self,
+        pump: &mut P,
+        flow: &F,
+        power: PumpPower,
+        amount: Milligrams,
+    ) {
+        pump.set_power(power);
+        self.start = flow.flowed();
+        self.amount = amount;
+        self.active = true;
+        pump.enable();
+    }
+
+    /// Stop the `pump` if the requested amount of water has been poured.
+    /// Returns `true` if the dispenser is still pouring.
+    pub fn update<P: PumpActuator, F: FlowSensor>(&mut self, pump: &mut P, flow: &F) -> bool {
+        if self.active && self.flowed(flow) >= self.amount {
+            self.stop(pump);
+        }
+        self.active
+    }
+
+    /// Stop pouring immediately.
+    pub fn stop<P: PumpActuator>(&mut self, pump: &mut P) {
+        pump.disable();
+        self.active = false;
+    }
+
+    /// Whether the dispenser is currently pouring.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// The amount of water poured since the last call to `start()`.
+    pub fn flowed<F: FlowSensor>(&self, flow: &F) -> Milligrams {
+        flow.flowed().wrapping_sub(self.start)
+    }
+
+    /// Pour `amount` of water and wait until it has been poured.
+    pub async fn dispense<P: PumpActuator, F: FlowSensor>(
+        &mut self,
+        pump: &mut P,
+        flow: &F,
+        power: PumpPower,
+        amount: Milligrams,
+    ) {
+        self.start(pump, flow, power, amount);
+        flow.wait_for_amount(amount).await;
+        self.stop(pump);
+    }
+}
@@ -1 +1,9 @@
-pub mod temperature_pid;
\ No newline at end of file
+//!
+//! This module contains the control logic of the portafilter machine. The logic is
+//! generic over the traits in [`crate::hardware::traits`].
+//!
+
+pub mod control_loop;
+pub mod dispenser;
+pub mod temperature_pid;
+pub mod thermoblock_observer;
@@ -1,9 +1,14 @@
-use core::time::Duration;
+//!
+//! PID controller used to keep the water at the target temperature.
+//!
 
-use embassy_time::Instant;
-
-use crate::hardware::temperature;
+use embassy_time::Duration;
 
+use super::thermoblock_observer::ThermoblockObserver;
+use crate::{
+    hardware::traits::{HeaterActuator, TemperatureSensor},
+    units::{MilliCelsius, MlPerSecond, PartsPerMillion, Percent},
+};
 
 #[allow(non_snake_case)]
 struct PidParameters {
@@ -18,61 +23,223 @@ const PARAMETERS: PidParameters = PidParameters {
     D: 0.0,
 };
 
-const MAX_OUTPUT_VALUE: u32 = 100;
-
-
+/// PID controller computing the heater power required to reach the target temperature.
+///
+/// By default, the temperature measured by the sensor is controlled. Optionally, the core
+/// temperature of the thermoblock estimated by a `ThermoblockObserver` is controlled instead,
+/// which reacts earlier to changes of the heater power or the water flow.
 pub struct TemperaturePID {
-    last_update: Option<Instant>,
-    last_temperature: Option<u32>,
-    target_temperatur: u32,
-    error: i32,
+    last_temperature: Option<f32>,
+    target_temperatur: f32,
+    error: f32,
+    observer: Option<ThermoblockObserver>,
+    flow_rate: MlPerSecond,
 }
 
 impl TemperaturePID {
-
+    /// Create a new `TemperaturePID` with a target temperature of 0°C.
     pub fn new() -> Self {
         TemperaturePID {
-            last_update: None,
             last_temperature: None,
-            target_temperatur: 0,
-            error: 0,
+            target_temperatur: 0.0,
+            error: 0.0,
+            observer: None,
+            flow_rate: MlPerSecond::ZERO,
         }
     }
 
-    pub fn set_target_temperature(&mut self, target_temperature: u32) {
-        self.target_temperatur = target_temperature;
-        self.error = 0;
-        self.last_update = None;
+    /// Control the core temperature estimated by `observer` instead of the temperature
+    /// measured by the sensor, or the measured temperature again if `observer` is `None`.
+    ///
+    /// The observer is only updated via `control`, which feeds it with the power the heater
+    /// applied in the previous step and the flow rate set via `set_flow_rate`.
+    pub fn set_observer(&mut self, observer: Option<ThermoblockObserver>) {
+        self.observer = observer;
         self.last_temperature = None;
     }
 
-    pub fn update(&mut self, current_temperature: u32) -> u32 {
-        let since_last_update = self.last_update.map(|e| e.elapsed());
-        let difference = self.target_temperatur as i32 - current_temperature as i32;
+    /// Set the rate of the water currently flowing through the thermoblock, which the
+    /// observer accounts for.
+    pub fn set_flow_rate(&mut self, flow_rate: MlPerSecond) {
+        self.flow_rate = flow_rate;
+    }
+
+    /// The core temperature estimated by the observer, or `None` if no observer is set or it
+    /// has no estimate yet.
+    pub fn core_temperature(&self) -> Option<MilliCelsius> {
+        self.observer
+            .as_ref()
+            .and_then(|observer| observer.core_temperature())
+    }
+
+    /// Set the temperature the controller should reach and reset its state.
+    pub fn set_target_temperature(&mut self, target_temperature: MilliCelsius) {
+        self.target_temperatur = target_temperature.as_celsius_f32();
+        self.error = 0.0;
+        self.last_temperature = None;
+    }
 
-        if let Some(since_last_update) = since_last_update {
-            let error = difference * since_last_update.as_millis() as i32;
-            if (self.error + error).abs() < 100 {
+    /// Feed the `current_temperature` into the controller and get the heater
+    /// power that should be applied next. `dt` is the time since the previous update.
+    ///
+    /// The temperature is processed with its full resolution, i.e., fractions of a degree
+    /// affect the output as well.
+    pub fn update(&mut self, current_temperature: MilliCelsius, dt: Duration) -> Percent {
+        self.update_fractional(current_temperature, dt).to_percent()
+    }
+
+    /// Like `update`, but returns the heater power without rounding it to whole percents.
+    pub fn update_fractional(
+        &mut self,
+        current_temperature: MilliCelsius,
+        dt: Duration,
+    ) -> PartsPerMillion {
+        let current_temperature = current_temperature.as_celsius_f32();
+        let difference = self.target_temperatur - current_temperature;
+        let mut derivative = 0f32;
+
+        if let Some(last_temperature) = self.last_temperature {
+            let error = difference * dt.as_millis() as f32;
+            if (self.error + error).abs() < 100.0 {
                 self.error += error;
             }
+
+            let elapsed_ms = dt.as_millis().max(1) as f32;
+            derivative = (current_temperature - last_temperature) / elapsed_ms;
         }
 
         self.last_temperature = Some(current_temperature);
-        self.last_update = Some(Instant::now());
-
-        let output = difference as f32 * PARAMETERS.P + self.error as f32 * PARAMETERS.I;
-        let output = 20 + output as i32;
-        if output < 0 {
-            0
-        } else if output > MAX_OUTPUT_VALUE.try_into().unwrap() {
-            MAX_OUTPUT_VALUE
-        } else  {
-            output as u32
-        }
+
+        let output =
+            difference * PARAMETERS.P + self.error * PARAMETERS.I - derivative * PARAMETERS.D;
+        PartsPerMillion::from_fraction_saturating((20.0 + output) / 100.0)
+    }
+
+    /// Read the temperature from `sensor`, update the controller and apply the resulting
+    /// fractional power to `heater`. `dt` is the time since the previous update. Returns the
+    /// power set rounded to the nearest percent.
+    ///
+    /// If an observer is set, the controller is updated with the estimated core temperature.
+    /// The heater is turned off while the sensor reports a fault.
+    pub fn control<T: TemperatureSensor, H: HeaterActuator>(
+        &mut self,
+        sensor: &T,
+        heater: &mut H,
+        dt: Duration,
+    ) -> Percent {
+        let power = match sensor.sensor_fault() {
+            Some(_) => {
+                self.last_temperature = None;
+                if let Some(observer) = &mut self.observer {
+                    observer.reset();
+                }
+                PartsPerMillion::ZERO
+            }
+            None => {
+                let mut temperature = sensor.temperature();
+                if let Some(observer) = &mut self.observer {
+                    let applied_power = heater.applied_power();
+                    temperature = observer.update(applied_power, self.flow_rate, temperature, dt);
+                }
+                self.update_fractional(temperature, dt)
+            }
+        };
+        heater.set_fractional_power(power);
+        power.to_percent()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        logic::thermoblock_observer::ObserverParameters,
+        simulator::{SimulationParameters, Simulator},
+    };
+
+    const DT: Duration = Duration::from_millis(50);
+
+    fn celsius(value: i32) -> MilliCelsius {
+        MilliCelsius::from_celsius(value).unwrap()
     }
 
+    #[test]
+    fn full_power_far_below_target() {
+        let mut pid = TemperaturePID::new();
+        pid.set_target_temperature(celsius(93));
+        assert_eq!(pid.update(celsius(20), DT), Percent::MAX);
+    }
 
+    #[test]
+    fn no_power_far_above_target() {
+        let mut pid = TemperaturePID::new();
+        pid.set_target_temperature(celsius(60));
+        assert_eq!(pid.update(celsius(90), DT), Percent::ZERO);
+    }
 
+    #[test]
+    fn base_power_at_target() {
+        let mut pid = TemperaturePID::new();
+        pid.set_target_temperature(celsius(60));
+        assert_eq!(pid.update(celsius(60), DT).get(), 20);
+    }
+
+    #[test]
+    fn reacts_to_fractions_of_a_degree() {
+        let mut pid = TemperaturePID::new();
+        pid.set_target_temperature(celsius(60));
+        assert!(pid.update(MilliCelsius::new(59_500), DT).get() > 20);
+        pid.set_target_temperature(celsius(60));
+        assert!(pid.update(MilliCelsius::new(60_500), DT).get() < 20);
+    }
 
+    /// A heater that tripped, i.e., applies no power regardless of the power set.
+    struct TrippedHeater;
 
-}
\ No newline at end of file
+    impl HeaterActuator for TrippedHeater {
+        fn set_power(&mut self, _power: Percent) {}
+
+        fn applied_power(&self) -> PartsPerMillion {
+            PartsPerMillion::ZERO
+        }
+    }
+
+    #[test]
+    fn observer_is_fed_the_applied_power() {
+        let simulator = Simulator::new(SimulationParameters::bambino());
+        let observer = ThermoblockObserver::new(ObserverParameters::bambino());
+        let mut pid = TemperaturePID::new();
+        pid.set_observer(Some(observer));
+        pid.set_target_temperature(celsius(93));
+        for _ in 0..200 {
+            simulator.step();
+            let power = pid.control(&simulator.temperature(), &mut TrippedHeater, DT);
+            assert_eq!(power, Percent::MAX);
+        }
+        // The requested full power does not heat the estimated core.
+        let core = pid.core_temperature().unwrap().as_celsius_f32();
+        let sensor = simulator.sensor_temperature_c();
+        assert!((core - sensor).abs() < 1.0, "core={}", core);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn output_saturates_far_from_target(target in 0i32..200, offset in 20i32..100) {
+            let mut pid = TemperaturePID::new();
+            pid.set_target_temperature(celsius(target));
+            proptest::prop_assert_eq!(pid.update(celsius(target - offset), DT), Percent::MAX);
+            pid.set_target_temperature(celsius(target));
+            proptest::prop_assert_eq!(pid.update(celsius(target + offset), DT), Percent::ZERO);
+        }
+
+        #[test]
+        fn output_decreases_with_temperature(target in 0i32..200, temperature in 0i32..200) {
+            let mut pid = TemperaturePID::new();
+            pid.set_target_temperature(celsius(target));
+            let colder = pid.update(celsius(temperature), DT);
+            pid.set_target_temperature(celsius(target));
+            let warmer = pid.update(celsius(temperature + 1), DT);
+            proptest::prop_assert!(warmer <= colder);
+        }
+    }
+}
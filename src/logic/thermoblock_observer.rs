@@ -0,0 +1,189 @@
+//!
+//! State observer estimating the temperature of the thermoblock core.
+//!
+//! The NTC measures the water just before it exits the thermoblock, so the temperature of the
+//! block itself changes well before the NTC notices. The observer runs a thermal model of the
+//! block driven by the heater power and the water flow alongside the machine and corrects it
+//! with the measured outlet temperature (Luenberger observer).
+//!
+
+use embassy_time::Duration;
+
+use crate::{
+    hardware::config::PROFILE,
+    units::{MilliCelsius, MlPerSecond, PartsPerMillion},
+};
+
+/// Specific heat capacity of water in J/(g*K).
+const WATER_SPECIFIC_HEAT: f32 = 4.186;
+
+/// Parameters of the thermal model and the gains of the observer.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ObserverParameters {
+    /// Electrical power of the heater at 100% duty in W.
+    pub heater_power_w: f32,
+    /// Heat capacity of the thermoblock (including the water inside) in J/K.
+    pub thermal_mass_j_per_k: f32,
+    /// Heat lost to the environment per Kelvin above ambient in W/K.
+    pub ambient_loss_w_per_k: f32,
+    /// Temperature of the environment in °C.
+    pub ambient_temperature_c: f32,
+    /// Temperature of the water entering the thermoblock in °C.
+    pub inlet_temperature_c: f32,
+    /// Time constant of the lag between the block and the NTC, including the dead time.
+    pub sensor_lag: Duration,
+    /// Gain in 1/s with which the error of the estimated outlet temperature corrects the
+    /// estimated core temperature.
+    pub core_gain: f32,
+    /// Gain in 1/s with which the error of the estimated outlet temperature corrects the
+    /// estimated outlet temperature.
+    pub sensor_gain: f32,
+}
+
+impl ObserverParameters {
+    /// Parameters approximating the thermoblock of a Bambino (BES450).
+    pub const fn bambino() -> Self {
+        ObserverParameters {
+            heater_power_w: PROFILE.heater_power_w as f32,
+            thermal_mass_j_per_k: 150.0,
+            ambient_loss_w_per_k: 1.2,
+            ambient_temperature_c: 22.0,
+            inlet_temperature_c: 22.0,
+            sensor_lag: Duration::from_millis(2500),
+            core_gain: 0.4,
+            sensor_gain: 0.4,
+        }
+    }
+}
+
+/// Estimates the core temperature of the thermoblock, see the module documentation.
+pub struct ThermoblockObserver {
+    parameters: ObserverParameters,
+    /// The estimated core and outlet temperature in °C, `None` until the first update.
+    estimate: Option<(f32, f32)>,
+}
+
+impl ThermoblockObserver {
+    /// Create a new `ThermoblockObserver`. The estimate starts at the first measured temperature.
+    pub fn new(parameters: ObserverParameters) -> Self {
+        ThermoblockObserver {
+            parameters,
+            estimate: None,
+        }
+    }
+
+    /// The parameters of the observer.
+    pub fn parameters(&self) -> &ObserverParameters {
+        &self.parameters
+    }
+
+    /// Discard the estimate, e.g., after the NTC reported a fault.
+    pub fn reset(&mut self) {
+        self.estimate = None;
+    }
+
+    /// The estimated core temperature, or `None` before the first update.
+    pub fn core_temperature(&self) -> Option<MilliCelsius> {
+        self.estimate
+            .and_then(|(core, _)| MilliCelsius::from_celsius_f32(core))
+    }
+
+    /// Advance the estimate by `dt` during which the heater was driven with `heater_power` and
+    /// `flow_rate` water flowed through the block, and correct it with the `measured` outlet
+    /// temperature. Returns the estimated core temperature.
+    pub fn update(
+        &mut self,
+        heater_power: PartsPerMillion,
+        flow_rate: MlPerSecond,
+        measured: MilliCelsius,
+        dt: Duration,
+    ) -> MilliCelsius {
+        let p = &self.parameters;
+        let measured_c = measured.as_celsius_f32();
+        let (core, outlet) = self.estimate.unwrap_or((measured_c, measured_c));
+        let dt = dt.as_micros() as f32 / 1_000_000f32;
+
+        let heating_w = p.heater_power_w * heater_power.as_fraction();
+        let ambient_loss_w = p.ambient_loss_w_per_k * (core - p.ambient_temperature_c);
+        // The water is approximated to weigh 1 g/ml and to leave the block at core temperature.
+        let water_loss_w = flow_rate.get() * WATER_SPECIFIC_HEAT * (core - p.inlet_temperature_c);
+        let lag = p.sensor_lag.as_micros().max(1) as f32 / 1_000_000f32;
+        let error = measured_c - outlet;
+
+        let core = core
+            + dt * ((heating_w - ambient_loss_w - water_loss_w) / p.thermal_mass_j_per_k
+                + p.core_gain * error);
+        let outlet = outlet + dt * ((core - outlet) / lag + p.sensor_gain * error);
+        self.estimate = Some((core, outlet));
+
+        MilliCelsius::from_celsius_f32(core).unwrap_or(measured)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hardware::traits::HeaterActuator,
+        simulator::{SimulationParameters, Simulator, SIMULATION_STEP},
+        units::Percent,
+    };
+
+    fn celsius(value: i32) -> MilliCelsius {
+        MilliCelsius::from_celsius(value).unwrap()
+    }
+
+    #[test]
+    fn estimate_starts_at_measured_temperature() {
+        let mut observer = ThermoblockObserver::new(ObserverParameters::bambino());
+        assert_eq!(observer.core_temperature(), None);
+        let dt = Duration::from_millis(50);
+        let core = observer.update(PartsPerMillion::ZERO, MlPerSecond::ZERO, celsius(22), dt);
+        assert!((core.get() - 22_000).abs() < 10);
+        observer.reset();
+        assert_eq!(observer.core_temperature(), None);
+    }
+
+    #[test]
+    fn estimate_converges_to_steady_state() {
+        let parameters = ObserverParameters::bambino();
+        let mut observer = ThermoblockObserver::new(parameters);
+        let dt = Duration::from_millis(50);
+        // At 3% of the power, heating and ambient losses are balanced at about 61 °C.
+        let power = Percent::new(3).unwrap();
+        let steady_state = parameters.ambient_temperature_c
+            + parameters.heater_power_w * power.as_fraction() / parameters.ambient_loss_w_per_k;
+        observer.update(power.into(), MlPerSecond::ZERO, celsius(22), dt);
+        for _ in 0..1200 {
+            let measured = MilliCelsius::from_celsius_f32(steady_state).unwrap();
+            observer.update(power.into(), MlPerSecond::ZERO, measured, dt);
+        }
+        let core = observer.core_temperature().unwrap().as_celsius_f32();
+        assert!((core - steady_state).abs() < 0.5, "core={}", core);
+    }
+
+    #[test]
+    fn estimate_leads_the_sensor_while_heating() {
+        let simulator = Simulator::new(SimulationParameters::bambino());
+        let mut observer = ThermoblockObserver::new(ObserverParameters::bambino());
+        simulator.heater().set_power(Percent::MAX);
+        for _ in 0..500 {
+            simulator.step();
+            let measured = MilliCelsius::from_celsius_f32(simulator.sensor_temperature_c());
+            let flow_rate = MlPerSecond::new(simulator.water_flow_g_per_s()).unwrap();
+            let measured = measured.unwrap();
+            observer.update(PartsPerMillion::MAX, flow_rate, measured, SIMULATION_STEP);
+        }
+
+        let block = simulator.block_temperature_c();
+        let sensor = simulator.sensor_temperature_c();
+        let core = observer.core_temperature().unwrap().as_celsius_f32();
+        assert!(
+            (core - block).abs() < (sensor - block).abs() / 2.0,
+            "block={} sensor={} core={}",
+            block,
+            sensor,
+            core
+        );
+    }
+}
@@ -0,0 +1,65 @@
+//!
+//! Model of the flow meter that emits a pulse each time a certain amount of water passed it.
+//!
+
+use embassy_time::Duration;
+
+use crate::{hardware::flow_meter::PulseConverter, units::Milligrams};
+
+use super::SIMULATION_STEP;
+
+/// The simulated flow meter.
+///
+/// The amount of water per pulse follows the same flow-rate dependent characteristic
+/// `PulseConverter` compensates for on the real machine.
+pub struct PulseFlowMeter {
+    converter: PulseConverter,
+    pending_mg: f32,
+    since_last_pulse: Duration,
+    flowed: Milligrams,
+    pulse_ctr: u32,
+}
+
+impl PulseFlowMeter {
+    /// Create a new flow meter that did not measure any water so far.
+    pub fn new() -> Self {
+        PulseFlowMeter {
+            converter: PulseConverter::new(),
+            pending_mg: 0.0,
+            since_last_pulse: Duration::from_secs(0),
+            flowed: Milligrams::ZERO,
+            pulse_ctr: 0,
+        }
+    }
+
+    /// Advance the model by one simulation step with `water_flow_g_per_s` water passing
+    /// the meter. Returns the new measured amount of water if a pulse was emitted.
+    pub fn step(&mut self, water_flow_g_per_s: f32) -> Option<Milligrams> {
+        let dt = SIMULATION_STEP.as_micros() as f32 / 1_000_000f32;
+        self.pending_mg += water_flow_g_per_s.max(0.0) * 1000.0 * dt;
+        self.since_last_pulse += SIMULATION_STEP;
+
+        let pulses_per_second = self.converter.pulses_per_second();
+        let mg_per_pulse = PulseConverter::amount_per_pulse(pulses_per_second).get() as f32;
+        if mg_per_pulse <= 0.0 || self.pending_mg < mg_per_pulse {
+            return None;
+        }
+
+        self.pending_mg -= mg_per_pulse;
+        let amount = self.converter.on_pulse(self.since_last_pulse);
+        self.since_last_pulse = Duration::from_secs(0);
+        self.flowed = self.flowed.wrapping_add(amount);
+        self.pulse_ctr += 1;
+        Some(self.flowed)
+    }
+
+    /// The amount of water measured so far.
+    pub fn flowed(&self) -> Milligrams {
+        self.flowed
+    }
+
+    /// Number of pulses emitted so far.
+    pub fn pulse_ctr(&self) -> u32 {
+        self.pulse_ctr
+    }
+}
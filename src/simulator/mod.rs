@@ -0,0 +1,486 @@
+//!
+//! A simulated Bambino consisting of physical models of the thermoblock, the pump and the
+//! flow meter. The simulated components implement the traits in [`crate::hardware::traits`],
+//! such that the control logic can be run in closed loop without a machine.
+//!
+//! The simulation does not depend on the wall clock. Instead, time only advances by calling
+//! [`Simulator::advance`].
+//!
+
+pub mod flow_meter;
+pub mod pump;
+pub mod thermoblock;
+
+use core::cell::RefCell;
+
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant};
+
+use crate::{
+    hardware::{
+        filter::RateEstimator,
+        temperature::{HeaterPhase, SensorFault, TemperatureReading, DEFAULT_RATE_WINDOW},
+        traits::{
+            FlowSensor, HeaterActuator, PumpActuator, PumpPower, TemperatureSensor,
+            WaterOutputKind, WaterPath,
+        },
+    },
+    units::{MilliCelsius, Milligrams, PartsPerMillion, Percent},
+};
+
+use self::{
+    flow_meter::PulseFlowMeter,
+    pump::{PumpParameters, VibratoryPump},
+    thermoblock::{Thermoblock, ThermoblockParameters},
+};
+
+/// The time the models are advanced per simulation step.
+pub const SIMULATION_STEP: Duration = Duration::from_millis(10);
+
+/// Parameters of all simulated components.
+#[derive(Clone, Copy)]
+pub struct SimulationParameters {
+    /// Parameters of the thermoblock.
+    pub thermoblock: ThermoblockParameters,
+    /// Parameters of the pump.
+    pub pump: PumpParameters,
+}
+
+impl SimulationParameters {
+    /// Parameters approximating a Bambino (BES450).
+    pub const fn bambino() -> Self {
+        SimulationParameters {
+            thermoblock: ThermoblockParameters::bambino(),
+            pump: PumpParameters::bambino(),
+        }
+    }
+}
+
+struct SimulationState {
+    thermoblock: Thermoblock,
+    pump: VibratoryPump,
+    flow_meter: PulseFlowMeter,
+    heater_power: Percent,
+    water_output: WaterOutputKind,
+    sensor_fault: Option<SensorFault>,
+    rate_estimator: RateEstimator,
+    elapsed: Duration,
+}
+
+/// A simulated machine.
+pub struct Simulator {
+    state: RefCell<SimulationState>,
+    flow_update: Signal<NoopRawMutex, Milligrams>,
+    temperature_update: Signal<NoopRawMutex, TemperatureReading>,
+}
+
+impl Simulator {
+    /// Create a new simulated machine that is turned off and at ambient temperature.
+    pub fn new(parameters: SimulationParameters) -> Self {
+        let state = SimulationState {
+            thermoblock: Thermoblock::new(parameters.thermoblock),
+            pump: VibratoryPump::new(parameters.pump),
+            flow_meter: PulseFlowMeter::new(),
+            heater_power: Percent::ZERO,
+            water_output: WaterOutputKind::Shower,
+            sensor_fault: None,
+            rate_estimator: RateEstimator::new(DEFAULT_RATE_WINDOW),
+            elapsed: Duration::from_secs(0),
+        };
+        Simulator {
+            state: RefCell::new(state),
+            flow_update: Signal::new(),
+            temperature_update: Signal::new(),
+        }
+    }
+
+    /// Advance the simulation by `duration`, rounded up to a multiple of `SIMULATION_STEP`.
+    pub fn advance(&self, duration: Duration) {
+        let steps = duration.as_ticks().div_ceil(SIMULATION_STEP.as_ticks());
+        for _ in 0..steps {
+            self.step();
+        }
+    }
+
+    /// Advance the simulation by a single `SIMULATION_STEP`.
+    pub fn step(&self) {
+        let mut state = self.state.borrow_mut();
+        let heater_duty = state.heater_power.as_fraction();
+        let water_flow = state.pump.flow_g_per_s();
+
+        state.thermoblock.step(heater_duty, water_flow);
+        let new_flowed = state.flow_meter.step(water_flow);
+        state.elapsed += SIMULATION_STEP;
+        let reading = match state.sensor_fault {
+            Some(_) => None,
+            None => {
+                let timestamp = Instant::from_ticks(state.elapsed.as_ticks());
+                let temperature =
+                    MilliCelsius::from_celsius_f32(state.thermoblock.sensor_temperature_c())
+                        .unwrap_or_default();
+                let rate = state.rate_estimator.update(timestamp, temperature);
+                let heater_phase = if state.heater_power > Percent::ZERO {
+                    HeaterPhase::On
+                } else {
+                    HeaterPhase::Off
+                };
+                Some(TemperatureReading {
+                    timestamp,
+                    temperature,
+                    rate,
+                    heater_phase,
+                })
+            }
+        };
+        drop(state);
+
+        if let Some(new_flowed) = new_flowed {
+            self.flow_update.signal(new_flowed);
+        }
+        if let Some(reading) = reading {
+            self.temperature_update.signal(reading);
+        }
+    }
+
+    /// The simulated time elapsed so far.
+    pub fn elapsed(&self) -> Duration {
+        self.state.borrow().elapsed
+    }
+
+    /// The actual temperature of the thermoblock in °C, which is not observable on the real machine.
+    pub fn block_temperature_c(&self) -> f32 {
+        self.state.borrow().thermoblock.block_temperature_c()
+    }
+
+    /// The temperature measured by the NTC in °C.
+    pub fn sensor_temperature_c(&self) -> f32 {
+        self.state.borrow().thermoblock.sensor_temperature_c()
+    }
+
+    /// Let the NTC report `fault`, or work properly again if `fault` is `None`.
+    pub fn set_sensor_fault(&self, fault: Option<SensorFault>) {
+        self.state.borrow_mut().sensor_fault = fault;
+    }
+
+    /// The power currently applied to the heater.
+    pub fn heater_power(&self) -> Percent {
+        self.state.borrow().heater_power
+    }
+
+    /// The water currently moved by the pump in g/s.
+    pub fn water_flow_g_per_s(&self) -> f32 {
+        self.state.borrow().pump.flow_g_per_s()
+    }
+
+    /// The way the water is currently dispensed.
+    pub fn water_output(&self) -> WaterOutputKind {
+        self.state.borrow().water_output
+    }
+
+    /// The simulated heater.
+    pub fn heater(&self) -> SimulatedHeater<'_> {
+        SimulatedHeater { simulator: self }
+    }
+
+    /// The simulated pump.
+    pub fn pump(&self) -> SimulatedPump<'_> {
+        SimulatedPump { simulator: self }
+    }
+
+    /// The simulated solenoid.
+    pub fn solenoid(&self) -> SimulatedSolenoid<'_> {
+        SimulatedSolenoid { simulator: self }
+    }
+
+    /// The simulated NTC.
+    pub fn temperature(&self) -> SimulatedTemperature<'_> {
+        SimulatedTemperature { simulator: self }
+    }
+
+    /// The simulated flow meter.
+    pub fn flow_meter(&self) -> SimulatedFlowMeter<'_> {
+        SimulatedFlowMeter { simulator: self }
+    }
+}
+
+/// The heater of a `Simulator`. The power is applied as average over the simulation step.
+pub struct SimulatedHeater<'a> {
+    simulator: &'a Simulator,
+}
+
+impl<'a> HeaterActuator for SimulatedHeater<'a> {
+    fn set_power(&mut self, power: Percent) {
+        self.simulator.state.borrow_mut().heater_power = power;
+    }
+
+    fn applied_power(&self) -> PartsPerMillion {
+        self.simulator.state.borrow().heater_power.into()
+    }
+}
+
+/// The pump of a `Simulator`.
+pub struct SimulatedPump<'a> {
+    simulator: &'a Simulator,
+}
+
+impl<'a> PumpActuator for SimulatedPump<'a> {
+    fn set_power(&mut self, power: PumpPower) {
+        self.simulator.state.borrow_mut().pump.set_power(power);
+    }
+
+    fn enable(&mut self) {
+        self.simulator.state.borrow_mut().pump.set_enabled(true);
+    }
+
+    fn disable(&mut self) {
+        self.simulator.state.borrow_mut().pump.set_enabled(false);
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.simulator.state.borrow().pump.is_enabled()
+    }
+}
+
+/// The solenoid of a `Simulator`.
+pub struct SimulatedSolenoid<'a> {
+    simulator: &'a Simulator,
+}
+
+impl<'a> WaterPath for SimulatedSolenoid<'a> {
+    fn switch(&mut self, output: WaterOutputKind) {
+        self.simulator.state.borrow_mut().water_output = output;
+    }
+}
+
+/// The NTC of a `Simulator`. A reading is taken every simulation step, its timestamp is
+/// the simulated time elapsed at that point. Since the heater power is applied as average,
+/// the heater is reported to be on whenever its power is above zero.
+pub struct SimulatedTemperature<'a> {
+    simulator: &'a Simulator,
+}
+
+impl<'a> TemperatureSensor for SimulatedTemperature<'a> {
+    fn temperature(&self) -> MilliCelsius {
+        MilliCelsius::from_celsius_f32(self.simulator.sensor_temperature_c()).unwrap_or_default()
+    }
+
+    fn sensor_fault(&self) -> Option<SensorFault> {
+        self.simulator.state.borrow().sensor_fault
+    }
+
+    async fn wait_for_update(&self) -> TemperatureReading {
+        self.simulator.temperature_update.wait().await
+    }
+}
+
+/// The flow meter of a `Simulator`.
+pub struct SimulatedFlowMeter<'a> {
+    simulator: &'a Simulator,
+}
+
+impl<'a> SimulatedFlowMeter<'a> {
+    /// Number of pulses emitted so far.
+    pub fn pulse_ctr(&self) -> u32 {
+        self.simulator.state.borrow().flow_meter.pulse_ctr()
+    }
+}
+
+impl<'a> FlowSensor for SimulatedFlowMeter<'a> {
+    fn flowed(&self) -> Milligrams {
+        self.simulator.state.borrow().flow_meter.flowed()
+    }
+
+    async fn wait_for_next_update(&self) -> Milligrams {
+        self.simulator.flow_update.wait().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::{
+        temperature_pid::TemperaturePID,
+        thermoblock_observer::{ObserverParameters, ThermoblockObserver},
+    };
+    use crate::units::CelsiusPerSecond;
+
+    #[test]
+    fn heater_warms_up_thermoblock_with_delay() {
+        let simulator = Simulator::new(SimulationParameters::bambino());
+        let ambient = simulator.sensor_temperature_c();
+
+        simulator.heater().set_power(Percent::MAX);
+        simulator.advance(Duration::from_secs(1));
+        assert!(simulator.block_temperature_c() > ambient + 5.0);
+        // The NTC did not notice anything yet due to the dead time.
+        assert!(simulator.sensor_temperature_c() < ambient + 0.5);
+
+        simulator.advance(Duration::from_secs(4));
+        assert!(simulator.sensor_temperature_c() > ambient + 10.0);
+    }
+
+    #[test]
+    fn thermoblock_cools_down_to_ambient() {
+        let simulator = Simulator::new(SimulationParameters::bambino());
+        simulator.heater().set_power(Percent::MAX);
+        simulator.advance(Duration::from_secs(5));
+        simulator.heater().set_power(Percent::ZERO);
+        simulator.pump().enable();
+        simulator.advance(Duration::from_secs(60));
+        assert!((simulator.block_temperature_c() - 22.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn pump_flow_depends_on_duty() {
+        let simulator = Simulator::new(SimulationParameters::bambino());
+        let mut pump = simulator.pump();
+        assert_eq!(simulator.water_flow_g_per_s(), 0.0);
+
+        pump.enable();
+        pump.set_power(PumpPower::Fraction(Percent::new(50).unwrap()));
+        let half = simulator.water_flow_g_per_s();
+        pump.set_power(PumpPower::Highest);
+        let full = simulator.water_flow_g_per_s();
+        assert!(0.0 < half && half < full);
+
+        pump.disable();
+        assert_eq!(simulator.water_flow_g_per_s(), 0.0);
+    }
+
+    #[test]
+    fn flow_meter_measures_pumped_water() {
+        let simulator = Simulator::new(SimulationParameters::bambino());
+        let mut pump = simulator.pump();
+        pump.set_power(PumpPower::Fraction(Percent::new(50).unwrap()));
+        pump.enable();
+
+        let duration = Duration::from_secs(10);
+        let expected_mg = simulator.water_flow_g_per_s() * 1000.0 * 10.0;
+        simulator.advance(duration);
+
+        let flow_meter = simulator.flow_meter();
+        assert!(flow_meter.pulse_ctr() > 0);
+        let error = (flow_meter.flowed().get() as f32 - expected_mg).abs() / expected_mg;
+        assert!(error < 0.05, "error={}", error);
+    }
+
+    #[test]
+    fn pid_keeps_temperature_in_closed_loop() {
+        let simulator = Simulator::new(SimulationParameters::bambino());
+        let mut heater = simulator.heater();
+        let sensor = simulator.temperature();
+        let mut pid = TemperaturePID::new();
+        pid.set_target_temperature(MilliCelsius::from_celsius(63).unwrap());
+
+        let period = Duration::from_millis(50);
+        for _ in 0..(120 * 20) {
+            pid.control(&sensor, &mut heater, period);
+            simulator.advance(period);
+        }
+        let temperature = simulator.sensor_temperature_c();
+        assert!(
+            (temperature - 63.0).abs() < 8.0,
+            "temperature={}",
+            temperature
+        );
+    }
+
+    /// Heat up to `target` °C via `pid` and return the highest temperature of the block.
+    fn block_overshoot(mut pid: TemperaturePID, target: i32) -> f32 {
+        let simulator = Simulator::new(SimulationParameters::bambino());
+        let mut heater = simulator.heater();
+        let sensor = simulator.temperature();
+        pid.set_target_temperature(MilliCelsius::from_celsius(target).unwrap());
+
+        let period = Duration::from_millis(50);
+        let mut highest = simulator.block_temperature_c();
+        for _ in 0..(60 * 20) {
+            pid.control(&sensor, &mut heater, period);
+            simulator.advance(period);
+            highest = highest.max(simulator.block_temperature_c());
+        }
+        highest - target as f32
+    }
+
+    #[test]
+    fn pid_on_core_estimate_overshoots_less() {
+        let measured = block_overshoot(TemperaturePID::new(), 93);
+        let mut pid = TemperaturePID::new();
+        let observer = ThermoblockObserver::new(ObserverParameters::bambino());
+        pid.set_observer(Some(observer));
+        let estimated = block_overshoot(pid, 93);
+        assert!(
+            estimated < measured / 2.0,
+            "measured={} estimated={}",
+            measured,
+            estimated
+        );
+    }
+
+    /// Run `simulator` until `future` completes.
+    fn run_until<F: core::future::Future>(simulator: &Simulator, future: F) -> F::Output {
+        let simulation = async {
+            loop {
+                simulator.step();
+                embassy_futures::yield_now().await;
+            }
+        };
+        match embassy_futures::block_on(embassy_futures::select::select(future, simulation)) {
+            embassy_futures::select::Either::First(output) => output,
+            embassy_futures::select::Either::Second(()) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn waits_until_temperature_thresholds() {
+        let simulator = Simulator::new(SimulationParameters::bambino());
+        let sensor = simulator.temperature();
+        let threshold = MilliCelsius::from_celsius(60).unwrap();
+        let timeout = Duration::from_secs(10);
+
+        simulator.heater().set_power(Percent::MAX);
+        let hot = run_until(&simulator, sensor.wait_until_above(threshold, timeout)).unwrap();
+        assert!(hot.temperature > threshold);
+        assert!(hot.rate.get() > 1.0, "rate={:?}", hot.rate);
+        assert_eq!(hot.timestamp.as_ticks(), simulator.elapsed().as_ticks());
+
+        simulator.heater().set_power(Percent::ZERO);
+        simulator.pump().enable();
+        let cold = run_until(&simulator, sensor.wait_until_below(threshold, timeout)).unwrap();
+        assert!(cold.temperature < threshold);
+        assert!(cold.rate < CelsiusPerSecond::ZERO, "rate={:?}", cold.rate);
+        assert!(cold.timestamp > hot.timestamp);
+    }
+
+    #[test]
+    fn waiting_for_unreachable_temperature_times_out() {
+        let simulator = Simulator::new(SimulationParameters::bambino());
+        let sensor = simulator.temperature();
+        let threshold = MilliCelsius::from_celsius(90).unwrap();
+        let result = run_until(
+            &simulator,
+            sensor.wait_until_above(threshold, Duration::from_millis(20)),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn pid_turns_heater_off_on_sensor_fault() {
+        let simulator = Simulator::new(SimulationParameters::bambino());
+        let mut heater = simulator.heater();
+        let sensor = simulator.temperature();
+        let mut pid = TemperaturePID::new();
+        pid.set_target_temperature(MilliCelsius::from_celsius(93).unwrap());
+
+        let period = Duration::from_millis(50);
+        pid.control(&sensor, &mut heater, period);
+        assert_eq!(simulator.heater_power(), Percent::MAX);
+
+        simulator.set_sensor_fault(Some(SensorFault::OpenCircuit));
+        assert_eq!(pid.control(&sensor, &mut heater, period), Percent::ZERO);
+        assert_eq!(simulator.heater_power(), Percent::ZERO);
+
+        simulator.set_sensor_fault(None);
+        assert_eq!(pid.control(&sensor, &mut heater, period), Percent::MAX);
+    }
+}
@@ -0,0 +1,83 @@
+//!
+//! Model of the vibratory pump.
+//!
+
+use crate::hardware::traits::PumpPower;
+
+/// Parameters of the pump model.
+#[derive(Clone, Copy)]
+pub struct PumpParameters {
+    /// Flow at 100% duty in g/s.
+    pub max_flow_g_per_s: f32,
+    /// Duty (0.0 - 1.0) below which the pump is not able to move any water.
+    pub min_duty: f32,
+    /// Duty (0.0 - 1.0) applied for `PumpPower::Lowest`.
+    pub lowest_duty: f32,
+}
+
+impl PumpParameters {
+    /// Parameters approximating the pump of a Bambino (BES450).
+    pub const fn bambino() -> Self {
+        PumpParameters {
+            max_flow_g_per_s: 6.5,
+            min_duty: 0.05,
+            lowest_duty: 0.1,
+        }
+    }
+}
+
+/// The simulated vibratory pump.
+pub struct VibratoryPump {
+    parameters: PumpParameters,
+    duty: f32,
+    enabled: bool,
+}
+
+impl VibratoryPump {
+    /// Create a new pump that is disabled and set to the highest power.
+    pub fn new(parameters: PumpParameters) -> Self {
+        VibratoryPump {
+            parameters,
+            duty: 1.0,
+            enabled: false,
+        }
+    }
+
+    /// Set the power of the pump to `power`.
+    pub fn set_power(&mut self, power: PumpPower) {
+        self.duty = match power {
+            PumpPower::Lowest => self.parameters.lowest_duty,
+            PumpPower::Highest => 1.0,
+            PumpPower::Fraction(frac) => frac.as_fraction(),
+        };
+    }
+
+    /// Turn the pump on or off.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Whether the pump is running.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The duty (0.0 - 1.0) the pump is currently driven with.
+    pub fn duty(&self) -> f32 {
+        if self.enabled {
+            self.duty
+        } else {
+            0.0
+        }
+    }
+
+    /// The water currently moved by the pump in g/s.
+    pub fn flow_g_per_s(&self) -> f32 {
+        let p = &self.parameters;
+        let duty = self.duty();
+        if duty <= p.min_duty {
+            return 0.0;
+        }
+        p.max_flow_g_per_s * (duty - p.min_duty) / (1.0 - p.min_duty)
+    }
+}
@@ -0,0 +1,117 @@
+//!
+//! Thermal model of the thermoblock and of the NTC measuring the outlet temperature.
+//!
+
+use embassy_time::Duration;
+
+use super::SIMULATION_STEP;
+use crate::hardware::config::PROFILE;
+
+/// Specific heat capacity of water in J/(g*K).
+const WATER_SPECIFIC_HEAT: f32 = 4.186;
+
+/// Maximum number of simulation steps the sensor may lag behind the thermoblock.
+const MAX_DEAD_TIME_STEPS: usize = 256;
+
+/// Parameters of the thermal model.
+#[derive(Clone, Copy)]
+pub struct ThermoblockParameters {
+    /// Electrical power of the heater at 100% duty in W.
+    pub heater_power_w: f32,
+    /// Heat capacity of the thermoblock (including the water inside) in J/K.
+    pub thermal_mass_j_per_k: f32,
+    /// Heat lost to the environment per Kelvin above ambient in W/K.
+    pub ambient_loss_w_per_k: f32,
+    /// Temperature of the environment in °C.
+    pub ambient_temperature_c: f32,
+    /// Temperature of the water entering the thermoblock in °C.
+    pub inlet_temperature_c: f32,
+    /// Time it takes until a change of the block temperature starts to affect the NTC.
+    pub dead_time: Duration,
+    /// Time constant of the first order lag between block and NTC.
+    pub sensor_time_constant: Duration,
+}
+
+impl ThermoblockParameters {
+    /// Parameters approximating the thermoblock of a Bambino (BES450).
+    pub const fn bambino() -> Self {
+        ThermoblockParameters {
+            heater_power_w: PROFILE.heater_power_w as f32,
+            thermal_mass_j_per_k: 150.0,
+            ambient_loss_w_per_k: 1.2,
+            ambient_temperature_c: 22.0,
+            inlet_temperature_c: 22.0,
+            dead_time: Duration::from_millis(1500),
+            sensor_time_constant: Duration::from_millis(1000),
+        }
+    }
+}
+
+/// The simulated thermoblock.
+pub struct Thermoblock {
+    parameters: ThermoblockParameters,
+    block_temperature_c: f32,
+    sensor_temperature_c: f32,
+    history: [f32; MAX_DEAD_TIME_STEPS],
+    history_idx: usize,
+    dead_time_steps: usize,
+}
+
+impl Thermoblock {
+    /// Create a new thermoblock that is at ambient temperature.
+    ///
+    /// # Panics
+    /// If the dead time of `parameters` exceeds the supported maximum.
+    pub fn new(parameters: ThermoblockParameters) -> Self {
+        let dead_time_steps =
+            (parameters.dead_time.as_ticks() / SIMULATION_STEP.as_ticks()) as usize;
+        assert!(dead_time_steps < MAX_DEAD_TIME_STEPS);
+        let ambient = parameters.ambient_temperature_c;
+        Thermoblock {
+            parameters,
+            block_temperature_c: ambient,
+            sensor_temperature_c: ambient,
+            history: [ambient; MAX_DEAD_TIME_STEPS],
+            history_idx: 0,
+            dead_time_steps,
+        }
+    }
+
+    /// Advance the model by one simulation step with the heater running at `heater_duty`
+    /// (0.0 - 1.0) and `water_flow_g_per_s` water flowing through the block.
+    pub fn step(&mut self, heater_duty: f32, water_flow_g_per_s: f32) {
+        let p = &self.parameters;
+        let dt = SIMULATION_STEP.as_micros() as f32 / 1_000_000f32;
+
+        let heating_w = p.heater_power_w * heater_duty.clamp(0.0, 1.0);
+        let ambient_loss_w =
+            p.ambient_loss_w_per_k * (self.block_temperature_c - p.ambient_temperature_c);
+        // The water leaves the block at block temperature.
+        let water_loss_w = water_flow_g_per_s.max(0.0)
+            * WATER_SPECIFIC_HEAT
+            * (self.block_temperature_c - p.inlet_temperature_c);
+
+        self.block_temperature_c +=
+            (heating_w - ambient_loss_w - water_loss_w) * dt / p.thermal_mass_j_per_k;
+
+        self.history[self.history_idx] = self.block_temperature_c;
+        let delayed_idx =
+            (self.history_idx + MAX_DEAD_TIME_STEPS - self.dead_time_steps) % MAX_DEAD_TIME_STEPS;
+        let delayed_temperature_c = self.history[delayed_idx];
+        self.history_idx = (self.history_idx + 1) % MAX_DEAD_TIME_STEPS;
+
+        let time_constant = p.sensor_time_constant.as_micros() as f32 / 1_000_000f32;
+        let alpha = dt / (time_constant + dt);
+        self.sensor_temperature_c += alpha * (delayed_temperature_c - self.sensor_temperature_c);
+    }
+
+    /// The temperature of the thermoblock in °C.
+    pub fn block_temperature_c(&self) -> f32 {
+        self.block_temperature_c
+    }
+
+    /// The temperature measured by the NTC in °C.
+    pub fn sensor_temperature_c(&self) -> f32 {
+        self.sensor_temperature_c
+    }
+}
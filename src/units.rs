@@ -0,0 +1,373 @@
+//!
+//! Newtypes for the physical units used throughout the public API, such that mixing up
+//! e.g. a temperature and a heater power is a compile error.
+//!
+
+use core::{
+    num::NonZeroU32,
+    ops::{Add, AddAssign},
+};
+
+/// A percentage in the range 0..=100.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Percent(u8);
+
+impl Percent {
+    /// 0%.
+    pub const ZERO: Percent = Percent(0);
+    /// 100%.
+    pub const MAX: Percent = Percent(100);
+
+    /// Create a new `Percent` if `value` is within 0..=100.
+    pub const fn new(value: u32) -> Option<Self> {
+        if value <= 100 {
+            Some(Percent(value as u8))
+        } else {
+            None
+        }
+    }
+
+    /// Create a new `Percent` by clamping `value` to 0..=100.
+    pub fn new_saturating(value: i32) -> Self {
+        Percent(value.clamp(0, 100) as u8)
+    }
+
+    /// Create a new `Percent` from a `fraction` within 0.0..=1.0.
+    pub fn from_fraction(fraction: f32) -> Option<Self> {
+        if (0.0..=1.0).contains(&fraction) {
+            Some(Percent((fraction * 100.0) as u8))
+        } else {
+            None
+        }
+    }
+
+    /// The percentage as integer within 0..=100.
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+
+    /// The percentage as fraction within 0.0..=1.0.
+    pub fn as_fraction(self) -> f32 {
+        self.0 as f32 / 100f32
+    }
+}
+
+/// A fraction in parts per million, i.e., in the range 0..=1_000_000. This is used where a
+/// `Percent` is too coarse, e.g., for the power of the heater.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PartsPerMillion(u32);
+
+impl PartsPerMillion {
+    /// 0%.
+    pub const ZERO: PartsPerMillion = PartsPerMillion(0);
+    /// 100%.
+    pub const MAX: PartsPerMillion = PartsPerMillion(1_000_000);
+
+    /// Create a new `PartsPerMillion` if `value` is within 0..=1_000_000.
+    pub const fn new(value: u32) -> Option<Self> {
+        if value <= 1_000_000 {
+            Some(PartsPerMillion(value))
+        } else {
+            None
+        }
+    }
+
+    /// Create a new `PartsPerMillion` from a `fraction` clamped to 0.0..=1.0. Not a number is
+    /// treated as zero.
+    pub fn from_fraction_saturating(fraction: f32) -> Self {
+        // NaN fails both comparisons and ends up as zero.
+        let fraction = if fraction > 0.0 { fraction } else { 0.0 };
+        let fraction = if fraction < 1.0 { fraction } else { 1.0 };
+        PartsPerMillion((fraction * 1_000_000.0) as u32)
+    }
+
+    /// The fraction in parts per million within 0..=1_000_000.
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+
+    /// The fraction within 0.0..=1.0.
+    pub fn as_fraction(self) -> f32 {
+        self.0 as f32 / 1_000_000f32
+    }
+
+    /// The fraction rounded to the nearest `Percent`.
+    pub const fn to_percent(self) -> Percent {
+        Percent(((self.0 + 5_000) / 10_000) as u8)
+    }
+}
+
+impl From<Percent> for PartsPerMillion {
+    fn from(value: Percent) -> Self {
+        PartsPerMillion(value.0 as u32 * 10_000)
+    }
+}
+
+/// A temperature in 1/1000 °C.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MilliCelsius(i32);
+
+impl MilliCelsius {
+    /// Create a new temperature of `value` m°C.
+    pub const fn new(value: i32) -> Self {
+        MilliCelsius(value)
+    }
+
+    /// Create a new temperature of `celsius` °C, or `None` if it is not representable.
+    pub const fn from_celsius(celsius: i32) -> Option<Self> {
+        match celsius.checked_mul(1000) {
+            Some(value) => Some(MilliCelsius(value)),
+            None => None,
+        }
+    }
+
+    /// Create a new temperature of `celsius` °C, or `None` if it is not representable.
+    pub fn from_celsius_f32(celsius: f32) -> Option<Self> {
+        let value = celsius * 1000.0;
+        if value.is_finite() && value >= i32::MIN as f32 && value <= i32::MAX as f32 {
+            Some(MilliCelsius(value as i32))
+        } else {
+            None
+        }
+    }
+
+    /// The temperature in m°C.
+    pub const fn get(self) -> i32 {
+        self.0
+    }
+
+    /// The temperature in whole °C, rounded towards zero.
+    pub const fn as_celsius(self) -> i32 {
+        self.0 / 1000
+    }
+
+    /// The temperature in °C.
+    pub fn as_celsius_f32(self) -> f32 {
+        self.0 as f32 / 1000f32
+    }
+}
+
+/// A mass of water in mg.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Milligrams(u32);
+
+impl Milligrams {
+    /// No water at all.
+    pub const ZERO: Milligrams = Milligrams(0);
+
+    /// Create a new mass of `value` mg.
+    pub const fn new(value: u32) -> Self {
+        Milligrams(value)
+    }
+
+    /// Create a new mass of `grams` g, or `None` if it is not representable.
+    pub const fn from_grams(grams: u32) -> Option<Self> {
+        match grams.checked_mul(1000) {
+            Some(value) => Some(Milligrams(value)),
+            None => None,
+        }
+    }
+
+    /// The mass in mg.
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+
+    /// Add `other` and wrap around on overflow, e.g., for total counters.
+    pub const fn wrapping_add(self, other: Milligrams) -> Milligrams {
+        Milligrams(self.0.wrapping_add(other.0))
+    }
+
+    /// Subtract `other` and wrap around on underflow, e.g., for the difference of two total counters.
+    pub const fn wrapping_sub(self, other: Milligrams) -> Milligrams {
+        Milligrams(self.0.wrapping_sub(other.0))
+    }
+}
+
+impl Add for Milligrams {
+    type Output = Milligrams;
+
+    fn add(self, rhs: Milligrams) -> Milligrams {
+        Milligrams(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Milligrams {
+    fn add_assign(&mut self, rhs: Milligrams) {
+        self.0 += rhs.0;
+    }
+}
+
+impl From<Millilitres> for Milligrams {
+    /// Convert a volume of water into its mass, assuming a density of 1 g/ml.
+    fn from(value: Millilitres) -> Self {
+        Milligrams(value.0.saturating_mul(1000))
+    }
+}
+
+/// A volume of water in ml.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Millilitres(u32);
+
+impl Millilitres {
+    /// Create a new volume of `value` ml.
+    pub const fn new(value: u32) -> Self {
+        Millilitres(value)
+    }
+
+    /// The volume in ml.
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+}
+
+impl From<Milligrams> for Millilitres {
+    /// Convert a mass of water into its volume (rounded down), assuming a density of 1 g/ml.
+    fn from(value: Milligrams) -> Self {
+        Millilitres(value.0 / 1000)
+    }
+}
+
+/// A water flow rate in ml/s.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MlPerSecond(f32);
+
+impl MlPerSecond {
+    /// No flow at all.
+    pub const ZERO: MlPerSecond = MlPerSecond(0.0);
+
+    /// Create a new flow rate of `value` ml/s, or `None` if `value` is negative or not finite.
+    pub fn new(value: f32) -> Option<Self> {
+        if value.is_finite() && value >= 0.0 {
+            Some(MlPerSecond(value))
+        } else {
+            None
+        }
+    }
+
+    /// The flow rate in ml/s.
+    pub fn get(self) -> f32 {
+        self.0
+    }
+}
+
+/// A rate of change of a temperature in °C/s.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CelsiusPerSecond(f32);
+
+impl CelsiusPerSecond {
+    /// A constant temperature.
+    pub const ZERO: CelsiusPerSecond = CelsiusPerSecond(0.0);
+
+    /// Create a new rate of `value` °C/s, or `None` if `value` is not finite.
+    pub fn new(value: f32) -> Option<Self> {
+        if value.is_finite() {
+            Some(CelsiusPerSecond(value))
+        } else {
+            None
+        }
+    }
+
+    /// The rate in °C/s.
+    pub fn get(self) -> f32 {
+        self.0
+    }
+}
+
+/// A frequency in Hz that is never zero.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Hertz(NonZeroU32);
+
+impl Hertz {
+    /// Create a new frequency of `value` Hz, or `None` if `value` is zero.
+    pub const fn new(value: u32) -> Option<Self> {
+        match NonZeroU32::new(value) {
+            Some(value) => Some(Hertz(value)),
+            None => None,
+        }
+    }
+
+    /// The frequency in Hz.
+    pub const fn get(self) -> u32 {
+        self.0.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_is_checked() {
+        assert_eq!(Percent::new(100), Some(Percent::MAX));
+        assert_eq!(Percent::new(101), None);
+        assert_eq!(Percent::new_saturating(-5), Percent::ZERO);
+        assert_eq!(Percent::new_saturating(250), Percent::MAX);
+        assert_eq!(Percent::from_fraction(0.5).map(Percent::get), Some(50));
+        assert_eq!(Percent::from_fraction(1.5), None);
+    }
+
+    #[test]
+    fn parts_per_million_conversions() {
+        assert_eq!(PartsPerMillion::new(1_000_001), None);
+        assert_eq!(PartsPerMillion::from(Percent::MAX), PartsPerMillion::MAX);
+        let fraction = PartsPerMillion::from_fraction_saturating(0.125);
+        assert_eq!(fraction.get(), 125_000);
+        assert_eq!(fraction.to_percent().get(), 13);
+        let saturating = PartsPerMillion::from_fraction_saturating;
+        assert_eq!(saturating(-1.0), PartsPerMillion::ZERO);
+        assert_eq!(saturating(2.0), PartsPerMillion::MAX);
+        assert_eq!(saturating(f32::NAN), PartsPerMillion::ZERO);
+    }
+
+    #[test]
+    fn milli_celsius_conversions() {
+        assert_eq!(
+            MilliCelsius::from_celsius(93),
+            Some(MilliCelsius::new(93_000))
+        );
+        assert_eq!(MilliCelsius::from_celsius(i32::MAX), None);
+        assert_eq!(MilliCelsius::new(-1_500).as_celsius(), -1);
+        assert_eq!(MilliCelsius::from_celsius_f32(f32::NAN), None);
+        assert_eq!(
+            MilliCelsius::from_celsius_f32(92.5),
+            Some(MilliCelsius::new(92_500))
+        );
+    }
+
+    #[test]
+    fn rates_are_finite() {
+        assert_eq!(
+            CelsiusPerSecond::new(-2.5).map(CelsiusPerSecond::get),
+            Some(-2.5)
+        );
+        assert_eq!(CelsiusPerSecond::new(f32::INFINITY), None);
+        assert_eq!(MlPerSecond::new(-1.0), None);
+    }
+
+    #[test]
+    fn water_conversions() {
+        assert_eq!(
+            Milligrams::from(Millilitres::new(36)),
+            Milligrams::new(36_000)
+        );
+        assert_eq!(
+            Millilitres::from(Milligrams::new(36_999)),
+            Millilitres::new(36)
+        );
+        assert_eq!(
+            Milligrams::new(5).wrapping_sub(Milligrams::new(10)),
+            Milligrams::new(u32::MAX - 4)
+        );
+        assert_eq!(MlPerSecond::new(-1.0), None);
+        assert_eq!(Hertz::new(0), None);
+    }
+}